@@ -1,13 +1,39 @@
-use clap::Parser;
-use lib::{compiler::Compiler, interpreter::Interpreter, lexer::tokenize_all};
-use std::time::Instant;
+use clap::{Parser, Subcommand};
+use lib::{
+    compiler::Compiler,
+    config::{CellWidth, EofBehavior, TapeConfig},
+    interpreter::Interpreter,
+    lexer::tokenize_all_with_spans,
+    passes::{Pass, PassManager, PassReport},
+    transpiler::{Target, Transpiler},
+};
+use std::{io::Write, os::unix::fs::PermissionsExt, path::Path, time::Instant};
 
 #[derive(Parser, Debug)]
 #[command(author="Thibaut de Saivre", version, about="JIT for brainfuck", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile a program into a standalone x86-64 Linux executable, an object file to link with, or a WebAssembly module
+    Build(BuildArgs),
+    /// Translate a program into source code in another language
+    Transpile(TranspileArgs),
+}
+
+/// Run a program
+#[derive(clap::Args, Debug)]
 struct Args {
     /// Source file
-    #[arg()]
-    source: String,
+    #[arg(required = true)]
+    source: Option<String>,
 
     /// Measure execution time
     #[arg(short, long)]
@@ -16,28 +42,202 @@ struct Args {
     /// Execute the program in interpreter mode, rather than JIT
     #[arg(short, long)]
     interpret: bool,
+
+    /// Flush the JIT output after every `.`, for interactive programs
+    #[arg(short, long)]
+    unbuffered: bool,
+
+    /// Check tape bounds in JIT mode (the interpreter always does)
+    #[arg(short, long)]
+    safe: bool,
+
+    /// Surround the JIT tape with guard pages, catching invalid accesses at no runtime cost
+    #[arg(short, long)]
+    guard_pages: bool,
+
+    /// Grow the tape when the pointer moves past its right end (uses guard pages in JIT mode)
+    #[arg(long)]
+    grow: bool,
+
+    /// Wrap the pointer around the edges of the tape
+    #[arg(long, conflicts_with = "grow")]
+    wrap: bool,
+
+    #[command(flatten)]
+    program: ProgramArgs,
+
+    #[command(flatten)]
+    optimization: OptimizationArgs,
+}
+
+/// Compile a program ahead of time
+#[derive(clap::Args, Debug)]
+struct BuildArgs {
+    /// Source file
+    #[arg()]
+    source: String,
+
+    /// File to write (defaults to the source file name with its extension replaced by `.out`, `.o` or `.wasm`)
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Write a relocatable object file defining `void bf_main(uint8_t *tape)`, instead of an executable
+    #[arg(short = 'c', long)]
+    object: bool,
+
+    /// Name of the function defined by the object file
+    #[arg(long, default_value = "bf_main", requires = "object")]
+    symbol: String,
+
+    /// Write a WebAssembly module exporting `run` and importing `env.putchar` and `env.getchar`
+    #[arg(long, conflicts_with = "object")]
+    wasm: bool,
+
+    /// Wrap the pointer around the edges of the tape
+    #[arg(long, conflicts_with = "wasm")]
+    wrap: bool,
+
+    #[command(flatten)]
+    program: ProgramArgs,
+
+    #[command(flatten)]
+    optimization: OptimizationArgs,
+}
+
+/// Translate a program into another language
+#[derive(clap::Args, Debug)]
+struct TranspileArgs {
+    /// Source file
+    #[arg()]
+    source: String,
+
+    /// Language to translate to: c, rust, wat (WebAssembly text)
+    #[arg(short = 'T', long)]
+    target: Target,
+
+    /// File to write (defaults to stdout)
+    #[arg(short, long)]
+    output: Option<String>,
+
+    #[command(flatten)]
+    program: ProgramArgs,
+
+    #[command(flatten)]
+    optimization: OptimizationArgs,
+}
+
+/// Behavior of the program
+#[derive(clap::Args, Debug)]
+struct ProgramArgs {
+    /// Value stored by `,` at end of input: unchanged, zero or minus-one
+    #[arg(short, long, default_value = "unchanged")]
+    eof: EofBehavior,
+
+    /// Number of cells on the tape
+    #[arg(long, default_value_t = 30_000, value_parser = clap::value_parser!(u64).range(1..))]
+    tape_size: u64,
+
+    /// Size of the tape cells in bits: 8, 16, 32 or 64
+    #[arg(long, default_value = "8")]
+    cell_width: CellWidth,
+}
+
+/// Optimization passes
+#[derive(clap::Args, Debug)]
+struct OptimizationArgs {
+    /// Optimization level, from 0 (none) to 3 (all passes)
+    #[arg(short = 'O', long, default_value_t = 3, value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: u8,
+
+    /// Enable optimization passes on top of the level: repetitions, patterns, scan-loops, multiply-loops, dead-loops, constants, pointer-offsets
+    #[arg(long, value_delimiter = ',')]
+    enable_pass: Vec<Pass>,
+
+    /// Disable optimization passes of the level
+    #[arg(long, value_delimiter = ',')]
+    disable_pass: Vec<Pass>,
+
+    /// Print which optimization passes changed the program
+    #[arg(long)]
+    report_passes: bool,
+}
+
+impl OptimizationArgs {
+    /// Passes enabled by the level and the individual toggles
+    fn passes(&self) -> PassManager {
+        let mut passes = PassManager::with_level(self.opt_level);
+        for pass in &self.enable_pass {
+            passes = passes.with_pass(*pass, true);
+        }
+        for pass in &self.disable_pass {
+            passes = passes.with_pass(*pass, false);
+        }
+        passes
+    }
 }
 
 fn main() -> std::io::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
+    match cli.command {
+        Some(Command::Build(args)) => build(args),
+        Some(Command::Transpile(args)) => transpile(args),
+        None => run(cli.run),
+    }
+}
+
+/// Execute a program with the interpreter or the JIT compiler
+fn run(args: Args) -> std::io::Result<()> {
     let start_time = Instant::now();
+    let source = args
+        .source
+        .expect("clap requires a source file without a subcommand");
 
     // Read the entire source code into a byte array
-    let bytes = std::fs::read(&args.source)?;
+    let bytes = std::fs::read(&source)?;
 
     // Tokenize the source code and remove invalid instructions
-    let source_code = tokenize_all(bytes);
+    let source_code = tokenize_all_with_spans(bytes);
+
+    let tape = TapeConfig {
+        size: args.program.tape_size as usize,
+        growable: args.grow,
+        wrapping: args.wrap,
+    };
+    let passes = args.optimization.passes();
 
-    if args.interpret {
+    let result = if args.interpret {
         // Execute the code in interpreter mode
-        let mut interpreter = Interpreter::new();
-        interpreter.execute(&source_code);
+        let mut interpreter = Interpreter::new()
+            .with_eof_behavior(args.program.eof)
+            .with_tape(tape)
+            .with_cell_width(args.program.cell_width)
+            .with_passes(passes);
+        let result = interpreter.execute(&source_code);
+        if args.optimization.report_passes {
+            print_pass_reports(interpreter.pass_reports());
+        }
+        result
     } else {
         // Execute the code in JIT mode
-        let mut compiler = Compiler::new();
-        compiler.compile(&source_code);
-        compiler.execute();
+        let mut compiler = Compiler::new()
+            .with_eof_behavior(args.program.eof)
+            .with_buffered_output(!args.unbuffered)
+            .with_bounds_check(args.safe)
+            .with_guard_pages(args.guard_pages)
+            .with_tape(tape)
+            .with_cell_width(args.program.cell_width)
+            .with_passes(passes);
+        let result = compiler.compile(&source_code);
+        if args.optimization.report_passes {
+            print_pass_reports(compiler.pass_reports());
+        }
+        result.and_then(|_| compiler.execute())
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}: {}", source, error);
+        std::process::exit(1);
     }
 
     // Measure the elapsed time
@@ -56,3 +256,131 @@ fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Compile a program into a standalone executable
+fn build(args: BuildArgs) -> std::io::Result<()> {
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None if args.object => default_output(&args.source, "o"),
+        None if args.wasm => default_output(&args.source, "wasm"),
+        None => default_output(&args.source, "out"),
+    };
+    check_output(&args.source, &output);
+
+    let bytes = std::fs::read(&args.source)?;
+    let source_code = tokenize_all_with_spans(bytes);
+
+    // WebAssembly modules are built from the same lowering as the transpiled languages
+    let result = if args.wasm {
+        let mut transpiler = Transpiler::new()
+            .with_tape_size(args.program.tape_size as usize)
+            .with_eof_behavior(args.program.eof)
+            .with_cell_width(args.program.cell_width)
+            .with_passes(args.optimization.passes());
+        let result = transpiler.compile_wasm(&source_code);
+        if args.optimization.report_passes {
+            print_pass_reports(transpiler.pass_reports());
+        }
+        result
+    } else {
+        let tape = TapeConfig {
+            size: args.program.tape_size as usize,
+            growable: false,
+            wrapping: args.wrap,
+        };
+        let mut compiler = Compiler::new()
+            .with_eof_behavior(args.program.eof)
+            .with_tape(tape)
+            .with_cell_width(args.program.cell_width)
+            .with_passes(args.optimization.passes());
+        let result = match args.object {
+            true => compiler.compile_object(&source_code, &args.symbol),
+            false => compiler.compile_executable(&source_code),
+        };
+        if args.optimization.report_passes {
+            print_pass_reports(compiler.pass_reports());
+        }
+        result
+    };
+    let file = match result {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("{}: {}", args.source, error);
+            std::process::exit(1);
+        }
+    };
+
+    let executable = !args.object && !args.wasm;
+    std::fs::write(&output, file)?;
+    match executable {
+        true => std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o755)),
+        false => Ok(()),
+    }
+}
+
+/// Translate a program into another language, written to a file or stdout
+fn transpile(args: TranspileArgs) -> std::io::Result<()> {
+    if let Some(output) = &args.output {
+        check_output(&args.source, output);
+    }
+
+    let bytes = std::fs::read(&args.source)?;
+    let source_code = tokenize_all_with_spans(bytes);
+
+    let mut transpiler = Transpiler::new()
+        .with_tape_size(args.program.tape_size as usize)
+        .with_eof_behavior(args.program.eof)
+        .with_cell_width(args.program.cell_width)
+        .with_passes(args.optimization.passes());
+
+    let result = transpiler.transpile(&source_code, args.target);
+    if args.optimization.report_passes {
+        print_pass_reports(transpiler.pass_reports());
+    }
+    let code = match result {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("{}: {}", args.source, error);
+            std::process::exit(1);
+        }
+    };
+
+    match args.output {
+        Some(output) => std::fs::write(output, code),
+        None => std::io::stdout().write_all(code.as_bytes()),
+    }
+}
+
+/// Output file next to the source file, with its extension replaced
+fn default_output(source: &str, extension: &str) -> String {
+    Path::new(source)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Exit with an error if writing the output file would overwrite the source file
+fn check_output(source: &str, output: &str) {
+    let overwrites = match (std::fs::canonicalize(source), std::fs::canonicalize(output)) {
+        (Ok(source), Ok(output)) => source == output,
+        _ => false,
+    };
+    if overwrites {
+        eprintln!("{source}: the output file {output} would overwrite the source file");
+        std::process::exit(1);
+    }
+}
+
+/// Print what each optimization pass did to the program on stderr
+fn print_pass_reports(reports: &[PassReport]) {
+    for report in reports {
+        let status = match report.changed {
+            true => "changed",
+            false => "unchanged",
+        };
+        eprintln!(
+            "{:<16} {:<9} {} -> {} instructions",
+            report.pass, status, report.before, report.after
+        );
+    }
+}
@@ -1,125 +1,664 @@
 //! JIT compiler implementation
 
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    io::{Read, Stdin, Stdout, Write},
+};
 
 use crate::{
+    config::{CellWidth, EofBehavior, TapeConfig, MAX_TAPE_GROWTH},
+    elf,
+    error::{tape_out_of_bounds, BfError},
     instructions::{ExtendedInstruction, Instruction},
-    optimizer::{
-        instructions_to_extended, optimize_instruction_repetitions, optimize_pattern_based,
-    },
+    io::read_input,
+    ir::Node,
+    lexer::Span,
+    passes::{Pass, PassManager, PassReport},
+    tape::{with_guard, GuardState, Tape, GUARD_SIZE},
+    x86_64,
 };
 use memmap2::{Mmap, MmapMut};
 
-pub struct Compiler {
+/// A JIT compiler for brainfuck.
+///
+/// The compiled code calls back into the host for `,` and `.`, which read from `R` and write to `W`.
+/// They default to stdin and stdout.
+pub struct Compiler<R: Read = Stdin, W: Write = Stdout> {
     /// Buffer for the generated machine code
     machine_code: Vec<u8>,
 
     /// Actual executable memory. The jump addresses will have to be resolved here.
     executable_memory: Mmap,
 
-    /// Memory buffer of 30_000 bytes
-    memory: Vec<u8>,
+    /// Memory tape of `tape.size` cells (30 000 by default), allocated on compilation
+    memory: Tape,
+    /// Size and edge behavior of the memory tape
+    tape: TapeConfig,
+    /// Size of the memory cells
+    cell_width: CellWidth,
+
+    /// What `,` stores in the current cell at end of input
+    eof_behavior: EofBehavior,
+
+    /// Whether `.` accumulates output in a buffer instead of calling the host every time
+    buffered_output: bool,
+
+    /// Whether pointer moves are checked against the tape bounds (safe mode)
+    bounds_check: bool,
+
+    /// Whether the tape is surrounded by guard pages that turn invalid accesses into errors
+    guard_pages: bool,
+
+    /// Whether the machine code runs on its own, with raw syscalls for I/O, rather than called by the host
+    standalone: bool,
+
+    /// Optimization passes run before compiling
+    passes: PassManager,
+    /// What each optimization pass did during the last compilation
+    pass_reports: Vec<PassReport>,
+
+    /// Source code of the last compilation, to locate faults precisely
+    source: Vec<(Instruction, Span)>,
+    /// Source location of each compiled instruction, indexed like the fault stubs
+    spans: Vec<Span>,
+    /// Pointer move (in cells) of each compiled instruction, at each step for scans
+    pointer_moves: Vec<i64>,
+    /// Machine code index of each compiled instruction
+    instruction_offsets: Vec<usize>,
+    /// Machine code index of the function epilogue
+    epilogue_index: usize,
+
+    /// Stream read by the `,` instruction
+    input: R,
+    /// Stream written by the `.` instruction
+    output: W,
 }
 
 impl Compiler {
+    /// Build a new compiler whose programs read from stdin and write to stdout
     pub fn new() -> Self {
         Self {
             machine_code: Vec::new(),
             executable_memory: MmapMut::map_anon(1).unwrap().make_exec().unwrap(),
-            memory: vec![0; 30_000],
+            memory: Tape::new(30_000, 0, 0).expect("the default tape fits in memory"),
+            tape: TapeConfig::default(),
+            cell_width: CellWidth::default(),
+            eof_behavior: EofBehavior::default(),
+            buffered_output: true,
+            bounds_check: false,
+            guard_pages: false,
+            standalone: false,
+            passes: PassManager::new(),
+            pass_reports: Vec::new(),
+            source: Vec::new(),
+            spans: Vec::new(),
+            pointer_moves: Vec::new(),
+            instruction_offsets: Vec::new(),
+            epilogue_index: 0,
+            input: std::io::stdin(),
+            output: std::io::stdout(),
+        }
+    }
+}
+
+impl<R: Read, W: Write> Compiler<R, W> {
+    /// Replace the stream read by the `,` instruction
+    pub fn with_input<I: Read>(self, input: I) -> Compiler<I, W> {
+        Compiler {
+            machine_code: self.machine_code,
+            executable_memory: self.executable_memory,
+            memory: self.memory,
+            tape: self.tape,
+            cell_width: self.cell_width,
+            eof_behavior: self.eof_behavior,
+            buffered_output: self.buffered_output,
+            bounds_check: self.bounds_check,
+            guard_pages: self.guard_pages,
+            standalone: self.standalone,
+            passes: self.passes,
+            pass_reports: self.pass_reports,
+            source: self.source,
+            spans: self.spans,
+            pointer_moves: self.pointer_moves,
+            instruction_offsets: self.instruction_offsets,
+            epilogue_index: self.epilogue_index,
+            input,
+            output: self.output,
+        }
+    }
+
+    /// Replace the stream written by the `.` instruction
+    pub fn with_output<O: Write>(self, output: O) -> Compiler<R, O> {
+        Compiler {
+            machine_code: self.machine_code,
+            executable_memory: self.executable_memory,
+            memory: self.memory,
+            tape: self.tape,
+            cell_width: self.cell_width,
+            eof_behavior: self.eof_behavior,
+            buffered_output: self.buffered_output,
+            bounds_check: self.bounds_check,
+            guard_pages: self.guard_pages,
+            standalone: self.standalone,
+            passes: self.passes,
+            pass_reports: self.pass_reports,
+            source: self.source,
+            spans: self.spans,
+            pointer_moves: self.pointer_moves,
+            instruction_offsets: self.instruction_offsets,
+            epilogue_index: self.epilogue_index,
+            input: self.input,
+            output,
         }
     }
 
+    /// Stream written by the `.` instruction
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Consume the compiler and return its output stream
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Cells of the tape, as left by the last execution.
+    /// With guard pages, the tape has more cells than configured, rounded up to whole pages.
+    pub fn tape(&self) -> Vec<u64> {
+        self.memory
+            .cells()
+            .chunks_exact(self.cell_width.bytes())
+            .map(|cell| {
+                let mut bytes = [0; 8];
+                bytes[..cell.len()].copy_from_slice(cell);
+                u64::from_le_bytes(bytes)
+            })
+            .collect()
+    }
+
+    /// Set what `,` stores in the current cell at end of input
+    pub fn with_eof_behavior(mut self, eof_behavior: EofBehavior) -> Self {
+        self.eof_behavior = eof_behavior;
+        self
+    }
+
+    /// Set whether pointer moves are checked against the tape bounds.
+    /// In safe mode, leaving the tape stops the program with a `TapeOutOfBounds` error instead of corrupting memory.
+    pub fn with_bounds_check(mut self, bounds_check: bool) -> Self {
+        self.bounds_check = bounds_check;
+        self
+    }
+
+    /// Set whether the tape is surrounded by guard pages.
+    /// Accessing a cell outside of the tape then stops the program with a `TapeOutOfBounds` error,
+    /// without any cost for valid accesses. The tape size is rounded up to a whole number of pages.
+    /// Moves are not checked: the error is located at the moves before the faulting access, except for scans,
+    /// which are assumed to start on the tape.
+    pub fn with_guard_pages(mut self, guard_pages: bool) -> Self {
+        self.guard_pages = guard_pages;
+        self
+    }
+
+    /// Set the size and edge behavior of the tape.
+    /// A growable tape always uses guard pages, and a wrapping tape never does.
+    pub fn with_tape(mut self, tape: TapeConfig) -> Self {
+        self.tape = tape;
+        self
+    }
+
+    /// Set the size of the tape cells
+    pub fn with_cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
+        self
+    }
+
+    /// Set whether `.` accumulates output in a buffer (the default), flushed when full, on input and at exit.
+    /// Unbuffered output is written and flushed on every `.`, which suits interactive programs.
+    pub fn with_buffered_output(mut self, buffered_output: bool) -> Self {
+        self.buffered_output = buffered_output;
+        self
+    }
+
+    /// Set the optimization passes run before compiling
+    pub fn with_passes(mut self, passes: PassManager) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// What each optimization pass did during the last compilation, in execution order
+    pub fn pass_reports(&self) -> &[PassReport] {
+        &self.pass_reports
+    }
+
     /// Compile the brainfuck source code into some machine code.
     /// The machine code will be stored inside this struct for later execution.
-    pub fn compile(&mut self, source: &[Instruction]) {
-        let memory_adress = self.memory.as_ptr();
-
-        // Add the mov r13, memory_address instruction into the machine code
-        self.machine_code.extend_from_slice(&[0x49, 0xbd]);
-        self.machine_code
-            .extend_from_slice(&(memory_adress as u64).to_le_bytes());
-
-        let instructions = instructions_to_extended(source);
-        let instructions = optimize_instruction_repetitions(&instructions);
-        let instructions = optimize_pattern_based(&instructions);
-
-        // Prepare [ to ] and ] to [ index hashmaps
-        let mut forward_jumps: HashMap<usize, usize> = HashMap::new();
-        let mut orphan_forwards: Vec<usize> = Vec::new();
-
-        // Compile the actual instructions
-        for instruction in instructions.iter() {
-            // Record the jump instruction indexes in the machine_code array before insertion
-            match instruction {
-                ExtendedInstruction::Regular(Instruction::JumpForward) => {
-                    orphan_forwards.push(self.machine_code.len())
-                } // It is actually the next byte that marks the jump address
-                ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
-                    let forward_index = orphan_forwards.pop().expect("Unmatched opening bracket");
-                    forward_jumps.insert(forward_index, self.machine_code.len());
-                }
-                _ => {}
-            }
+    pub fn compile(&mut self, source: &[(Instruction, Span)]) -> Result<(), BfError> {
+        let nodes = self.optimize(source)?;
 
-            // Add each instruction's corresponding byte slice to the machine code
-            let vec: Vec<u8> = instruction.into();
-            self.machine_code.extend(vec);
-        }
+        // Allocate the tape: its address is part of the machine code
+        let size = self.tape.bytes(self.cell_width)?;
+        self.memory = match (self.uses_guard_pages(), self.is_growable()) {
+            (true, true) => Tape::new(size, GUARD_SIZE, MAX_TAPE_GROWTH * self.cell_width.bytes()),
+            (true, false) => Tape::new(size, GUARD_SIZE, 0),
+            (false, _) => Tape::new(size, 0, 0),
+        }?;
 
-        assert!(
-            orphan_forwards.is_empty(),
-            "There exists unmatched opening brackets"
-        );
+        // Save the registers and load the context and memory addresses
+        let bounds = self.memory.bounds();
+        self.machine_code = x86_64::prologue(bounds.start, bounds.end);
 
-        // Last: append the RET instruction
-        self.machine_code.push(0xc3);
+        // Compile the actual instructions, recording the machine code index of each bounds check
+        // along with its instruction index
+        let mut bounds_checks: Vec<(usize, usize)> = Vec::new();
+        self.source = source.to_vec();
+        self.spans.clear();
+        self.pointer_moves.clear();
+        self.instruction_offsets.clear();
+        self.compile_nodes(&nodes, &mut bounds_checks);
 
-        // Finally: copy the machine code into the executable memory, and replace the jump instructions
-        // Create an anonymous memory map the size of our machine code
-        let mut temp_memory = MmapMut::map_anon(self.machine_code.len()).unwrap();
-        temp_memory.clone_from_slice(&self.machine_code); // Clone the machine code into it
-
-        // Record the memory address of each bracket jump instruction
-        for (start_index, end_index) in forward_jumps.iter() {
-            // Compute the memory addresses of the jump instructions
-            let start_address = unsafe { temp_memory.as_ptr().add(*start_index) };
-            let end_address = unsafe { temp_memory.as_ptr().add(*end_index) };
-
-            // Compute the relative signed offset.
-            let forward_offset = end_address as i32 - start_address as i32;
-            let backwards_offset = -forward_offset;
-
-            // Replace the jump addresses placeholders
-            // The total jump instruction has 11 bytes. We want to replace the last 4: offset of 8
-            temp_memory[start_index + 8..start_index + 12]
-                .copy_from_slice(forward_offset.to_le_bytes().as_ref());
-            temp_memory[end_index + 8..end_index + 12]
-                .copy_from_slice(backwards_offset.to_le_bytes().as_ref());
+        // Last: restore the registers and return
+        self.epilogue_index = self.machine_code.len();
+        self.machine_code.extend(x86_64::epilogue());
+
+        // Out of the normal flow: the fault stubs targeted by the bounds checks
+        for (check_index, instruction_index) in bounds_checks {
+            let stub_index = self.machine_code.len();
+            self.machine_code
+                .extend(x86_64::bounds_fault(instruction_index as u32));
+
+            patch_jump(&mut self.machine_code, check_index + 9, stub_index);
+            patch_jump(&mut self.machine_code, check_index + 18, stub_index);
+            patch_jump(&mut self.machine_code, stub_index + 19, self.epilogue_index);
         }
 
+        // Finally: copy the machine code into the executable memory
+        let mut temp_memory = MmapMut::map_anon(self.machine_code.len()).unwrap();
+        temp_memory.clone_from_slice(&self.machine_code);
+
         // Make the memory map executable
         self.executable_memory = temp_memory.make_exec().unwrap();
+
+        Ok(())
+    }
+
+    /// Compile the brainfuck source code into a standalone x86-64 Linux executable, returned as the bytes of an ELF file.
+    ///
+    /// The executable reads from stdin and writes to stdout with raw syscalls, and exits with status 0 at the end.
+    /// Its tape of `tape.size` cells is in `.bss`: it cannot grow, and bounds checks and guard pages are not available.
+    pub fn compile_executable(
+        &mut self,
+        source: &[(Instruction, Span)],
+    ) -> Result<Vec<u8>, BfError> {
+        let nodes = self.optimize(source)?;
+
+        // The tape address depends on the size of the code:
+        // compile with placeholder addresses first, then patch the prologue
+        let prologue = x86_64::prologue(std::ptr::null(), std::ptr::null());
+        self.compile_standalone(&nodes, prologue, x86_64::exit());
+
+        let size = self.tape.bytes(self.cell_width)?;
+        let tape = elf::tape_address(self.machine_code.len()) as usize;
+        let end = tape.checked_add(size).ok_or(BfError::TapeTooLarge)?;
+        let prologue = x86_64::prologue(tape as *const u8, end as *const u8);
+        self.machine_code[..prologue.len()].copy_from_slice(&prologue);
+
+        Ok(elf::executable(&self.machine_code, size))
+    }
+
+    /// Compile the brainfuck source code into a relocatable x86-64 object file, returned as the bytes of an ELF file.
+    ///
+    /// The object defines a global function `symbol`, callable from C as `void symbol(uint8_t *tape)`.
+    /// The caller provides a zeroed tape of `tape.size` cells of the configured width.
+    /// Like executables, the function reads from stdin and writes to stdout with raw syscalls,
+    /// and bounds checks and guard pages are not available.
+    pub fn compile_object(
+        &mut self,
+        source: &[(Instruction, Span)],
+        symbol: &str,
+    ) -> Result<Vec<u8>, BfError> {
+        let nodes = self.optimize(source)?;
+
+        let size = self.tape.bytes(self.cell_width)?;
+        self.compile_standalone(
+            &nodes,
+            x86_64::object_prologue(size as u64),
+            x86_64::epilogue(),
+        );
+
+        Ok(elf::object(&self.machine_code, symbol))
+    }
+
+    /// Compile the program tree with raw syscalls for I/O, between the given prologue and ending.
+    /// Jumps only use relative offsets, so the machine code does not depend on its address.
+    fn compile_standalone(&mut self, nodes: &[Node], prologue: Vec<u8>, ending: Vec<u8>) {
+        self.standalone = true;
+        self.machine_code = prologue;
+        self.spans.clear();
+        self.pointer_moves.clear();
+        self.instruction_offsets.clear();
+        self.compile_nodes(nodes, &mut Vec::new());
+        self.machine_code.extend(ending);
+        self.standalone = false;
     }
 
-    /// Execute the compiled machine code
-    pub fn execute(&self) {
+    /// Run the optimization passes, recording their reports
+    fn optimize(&mut self, source: &[(Instruction, Span)]) -> Result<Vec<Node>, BfError> {
+        // Cells at an offset from the pointer are not wrapped around,
+        // and cell positions tracked by constant propagation would alias
+        let passes = match self.tape.wrapping {
+            true => self
+                .passes
+                .clone()
+                .with_pass(Pass::PointerOffsets, false)
+                .with_pass(Pass::Constants, false),
+            false => self.passes.clone(),
+        };
+        let (nodes, reports) = passes.run(source, self.cell_width)?;
+        self.pass_reports = reports;
+        Ok(nodes)
+    }
+
+    /// Execute the compiled machine code.
+    /// The tape is kept between runs, but the code assumes it starts zeroed: call `clear` before running it again.
+    pub fn execute(&mut self) -> Result<(), BfError> {
         assert!(!self.machine_code.is_empty(), "No machine code to execute");
 
         // Get a pointer to the machine code
         let func_ptr = self.executable_memory.as_ptr();
 
-        unsafe {
-            let main: extern "C" fn() = std::mem::transmute(func_ptr);
-            main();
+        let uses_guard_pages = self.uses_guard_pages();
+        let mut buffer = vec![0; OUTPUT_BUFFER_SIZE];
+        let mut context = JitContext {
+            buffer: buffer.as_mut_ptr(),
+            buffer_len: 0,
+            buffer_capacity: buffer.len(),
+            fault: NO_FAULT,
+            pointer: 0,
+            input: &mut self.input,
+            output: &mut self.output,
+            eof_behavior: self.eof_behavior,
+            cell_width: self.cell_width,
+            error: None,
+        };
+
+        let main: extern "C" fn(*mut JitContext) = unsafe { std::mem::transmute(func_ptr) };
+
+        // With guard pages, faults in the guard regions resume execution at the epilogue
+        let guard_fault = if uses_guard_pages {
+            let code = func_ptr as usize..func_ptr as usize + self.executable_memory.len();
+            let state = GuardState {
+                guards: self.memory.guards(),
+                reserve: Cell::new(self.memory.reserve()),
+                exit: code.start + self.epilogue_index,
+                code,
+                fault: Cell::new(None),
+                pointer: Cell::new(0),
+            };
+            with_guard(&state, || main(&mut context));
+
+            // Keep the cells made accessible by the signal handler
+            self.memory.grow_to(state.reserve.take().start);
+            state
+                .fault
+                .get()
+                .map(|offset| (offset, state.pointer.get()))
+        } else {
+            main(&mut context);
+            None
+        };
+
+        // Write what remains in the output buffer
+        context.flush_buffer();
+        let (fault, pointer) = (context.fault, context.pointer);
+
+        match context.error {
+            Some(error) => Err(error.into()),
+            None if fault != NO_FAULT => Err(self.out_of_bounds(fault, pointer)),
+            None if guard_fault.is_some() => {
+                // Find the instruction whose machine code contains the faulting offset
+                let (offset, pointer) = guard_fault.unwrap();
+                let index = self
+                    .instruction_offsets
+                    .partition_point(|start| *start <= offset);
+                Err(self.out_of_bounds(index - 1, pointer))
+            }
+            None => Ok(self.output.flush()?),
+        }
+    }
+
+    /// `TapeOutOfBounds` error for the compiled instruction at the given index, faulting with the pointer
+    /// at the given address.
+    ///
+    /// Moves and scans fault once the pointer left the tape: their last step is undone to find where they started.
+    /// With guard pages, moves are not checked, and the next access faults: the error is located at those moves.
+    fn out_of_bounds(&self, mut index: usize, pointer: usize) -> BfError {
+        let bounds = self.memory.bounds();
+        let width = self.cell_width.bytes() as i64;
+        let len = (bounds.end as i64 - bounds.start as i64) / width;
+        let on_tape = |cell: i64| (0..len).contains(&cell);
+
+        let mut cell = (pointer as i64 - bounds.start as i64).div_euclid(width);
+        let step = self.pointer_moves[index];
+        while !on_tape(cell) && step != 0 && (cell < 0) == (step < 0) {
+            cell -= step;
+        }
+        while !on_tape(cell) && index > 0 && self.pointer_moves[index - 1] != 0 {
+            index -= 1;
+            cell -= self.pointer_moves[index];
         }
+
+        tape_out_of_bounds(&self.source, self.spans[index], cell, len)
+    }
+
+    /// Append the machine code of the given nodes
+    fn compile_nodes(&mut self, nodes: &[Node], bounds_checks: &mut Vec<(usize, usize)>) {
+        for node in nodes {
+            match node {
+                Node::Instruction(instruction, span) => {
+                    self.compile_instruction(instruction, *span, bounds_checks)
+                }
+                Node::Loop { body, open, close } => {
+                    let forward = ExtendedInstruction::Regular(Instruction::JumpForward);
+                    self.compile_instruction(&forward, *open, bounds_checks);
+                    let body_start = self.machine_code.len();
+
+                    self.compile_nodes(body, bounds_checks);
+
+                    let backward = ExtendedInstruction::Regular(Instruction::JumpBackwards);
+                    self.compile_instruction(&backward, *close, bounds_checks);
+                    let loop_end = self.machine_code.len();
+
+                    // The jump offsets of the brackets end with their machine code:
+                    // each bracket jumps right after its matching bracket
+                    patch_jump(&mut self.machine_code, body_start, loop_end);
+                    patch_jump(&mut self.machine_code, loop_end, body_start);
+                }
+            }
+        }
+    }
+
+    /// Append the machine code of a single instruction, which comes from the given span
+    fn compile_instruction(
+        &mut self,
+        instruction: &ExtendedInstruction,
+        span: Span,
+        bounds_checks: &mut Vec<(usize, usize)>,
+    ) {
+        let index = self.spans.len();
+        self.spans.push(span);
+        self.pointer_moves.push(pointer_move(instruction));
+        self.instruction_offsets.push(self.machine_code.len());
+
+        // In safe mode, check the address of cells at an offset from the pointer before accessing them
+        let offset = x86_64::displacement(cell_offset(instruction), self.cell_width);
+        let guard_check = self.uses_guard_pages() && offset.unsigned_abs() as usize >= GUARD_SIZE;
+        if offset != 0 && (self.checks_bounds() || guard_check) {
+            self.machine_code
+                .extend(x86_64::cell_address(offset as i64));
+            bounds_checks.push((self.machine_code.len(), index));
+            self.machine_code.extend(x86_64::address_bounds_check());
+        }
+
+        // Add each instruction's corresponding byte slice to the machine code
+        let vec: Vec<u8> = match instruction {
+            ExtendedInstruction::Regular(Instruction::Output)
+            | ExtendedInstruction::Output { .. }
+            | ExtendedInstruction::OutputValue(_)
+                if self.standalone =>
+            {
+                x86_64::encode(instruction, self.cell_width)
+            }
+            ExtendedInstruction::Regular(Instruction::Input) if self.standalone => {
+                x86_64::input(self.eof_behavior, self.cell_width)
+            }
+            ExtendedInstruction::Regular(Instruction::Output)
+            | ExtendedInstruction::Output { .. }
+                if self.buffered_output =>
+            {
+                x86_64::buffered_output(jit_flush as *const (), offset)
+            }
+            ExtendedInstruction::Regular(Instruction::Output)
+            | ExtendedInstruction::Output { .. } => {
+                x86_64::output_callback(jit_output as *const (), offset)
+            }
+            ExtendedInstruction::OutputValue(value) if self.buffered_output => {
+                x86_64::buffered_output_value(jit_flush as *const (), *value)
+            }
+            ExtendedInstruction::OutputValue(value) => {
+                x86_64::output_value_callback(jit_output as *const (), *value)
+            }
+            ExtendedInstruction::Regular(Instruction::Input) => {
+                x86_64::input_callback(jit_input as *const (), self.cell_width)
+            }
+            // Wraparound only handles moves smaller than the tape
+            ExtendedInstruction::JumpRight(n) if self.tape.wrapping => {
+                let n = (*n as usize % self.tape.size) as u32;
+                x86_64::encode(&ExtendedInstruction::JumpRight(n), self.cell_width)
+            }
+            ExtendedInstruction::JumpLeft(n) if self.tape.wrapping => {
+                let n = (*n as usize % self.tape.size) as u32;
+                x86_64::encode(&ExtendedInstruction::JumpLeft(n), self.cell_width)
+            }
+            ExtendedInstruction::MulAdd { offset, factor } => {
+                self.mul_add(*offset, *factor, index, bounds_checks)
+            }
+            ExtendedInstruction::ScanRight(stride) => {
+                self.scan(*stride as i64, index, bounds_checks)
+            }
+            ExtendedInstruction::ScanLeft(stride) => {
+                self.scan(-(*stride as i64), index, bounds_checks)
+            }
+            _ => x86_64::encode(instruction, self.cell_width),
+        };
+        self.machine_code.extend(vec);
+
+        // In safe mode, check the tape pointer after each move.
+        // Guard pages only catch moves that do not jump over a whole guard region.
+        let check = match instruction {
+            ExtendedInstruction::JumpRight(n) | ExtendedInstruction::JumpLeft(n) => {
+                let bytes = *n as usize * self.cell_width.bytes();
+                self.checks_bounds() || (self.uses_guard_pages() && bytes >= GUARD_SIZE)
+            }
+            _ => self.checks_bounds() && moves_pointer(instruction),
+        };
+        if self.tape.wrapping && moves_pointer(instruction) {
+            self.machine_code.extend(x86_64::wraparound());
+        } else if check {
+            bounds_checks.push((self.machine_code.len(), index));
+            self.machine_code.extend(x86_64::bounds_check());
+        }
+    }
+
+    /// Machine code for a `MulAdd` instruction at the given index.
+    /// The target cell address is checked or wrapped around like the tape pointer after a move.
+    fn mul_add(
+        &self,
+        offset: i32,
+        factor: u64,
+        index: usize,
+        bounds_checks: &mut Vec<(usize, usize)>,
+    ) -> Vec<u8> {
+        let start = self.machine_code.len();
+        let size = self.cell_width.bytes() as i64;
+
+        let mut bytes = x86_64::skip_if_zero(self.cell_width);
+        let skip = bytes.len();
+
+        if self.tape.wrapping {
+            // Wraparound only handles offsets smaller than the tape
+            let offset = (offset as i64).rem_euclid(self.tape.size as i64);
+            bytes.extend(x86_64::cell_address(offset * size));
+            bytes.extend(x86_64::address_wraparound());
+        } else {
+            let offset = offset as i64 * size;
+            bytes.extend(x86_64::cell_address(offset));
+
+            let guard_check =
+                self.uses_guard_pages() && offset.unsigned_abs() as usize >= GUARD_SIZE;
+            if self.checks_bounds() || guard_check {
+                bounds_checks.push((start + bytes.len(), index));
+                bytes.extend(x86_64::address_bounds_check());
+            }
+        }
+
+        bytes.extend(x86_64::mul_add(factor, self.cell_width));
+        let done = bytes.len();
+        patch_jump(&mut bytes, skip, done);
+        bytes
+    }
+
+    /// Machine code for a `ScanRight` or `ScanLeft` instruction at the given index, moving by `stride` cells.
+    /// Scans by one cell are vectorized, unless every move must be checked or wrapped around.
+    fn scan(&self, stride: i64, index: usize, bounds_checks: &mut Vec<(usize, usize)>) -> Vec<u8> {
+        let start = self.machine_code.len();
+        let step = stride * self.cell_width.bytes() as i64;
+
+        let check = if self.tape.wrapping {
+            x86_64::wraparound()
+        } else if self.checks_bounds()
+            || (self.uses_guard_pages() && step.unsigned_abs() as usize >= GUARD_SIZE)
+        {
+            x86_64::bounds_check()
+        } else {
+            let vectorized = match stride {
+                1 => x86_64::scan_right(self.cell_width),
+                -1 => x86_64::scan_left(self.cell_width),
+                _ => None,
+            };
+            return vectorized.unwrap_or_else(|| x86_64::scan_loop(step, self.cell_width, &[]));
+        };
+
+        // Wraparound only handles moves smaller than the tape
+        let step = match self.tape.wrapping {
+            true => stride.rem_euclid(self.tape.size as i64) * self.cell_width.bytes() as i64,
+            false => step,
+        };
+        let bytes = x86_64::scan_loop(step, self.cell_width, &check);
+        if !self.tape.wrapping {
+            bounds_checks.push((start + bytes.len() - 5 - check.len(), index));
+        }
+        bytes
+    }
+
+    /// Whether the tape grows when the pointer moves past its right end
+    fn is_growable(&self) -> bool {
+        self.tape.growable && !self.tape.wrapping && !self.standalone
+    }
+
+    /// Whether the tape is surrounded by guard pages
+    fn uses_guard_pages(&self) -> bool {
+        (self.guard_pages || self.tape.growable) && !self.tape.wrapping && !self.standalone
+    }
+
+    /// Whether pointer moves are checked against the tape bounds.
+    /// Standalone executables have no host to report errors to.
+    fn checks_bounds(&self) -> bool {
+        self.bounds_check && !self.standalone
     }
 
     /// Clear the compiler from its previous run (reset the memory in place)
     pub fn clear(&mut self) {
-        for i in 0..30_000 {
-            self.memory[i] = 0;
-        }
+        self.memory.clear();
     }
 }
 
@@ -128,3 +667,142 @@ impl Default for Compiler {
         Compiler::new()
     }
 }
+
+// ********************************************************************************************* //
+//                                           HELPER FUNCTIONS                                    //
+// ********************************************************************************************* //
+
+/// Helper: whether the instruction moves the tape pointer
+fn moves_pointer(instruction: &ExtendedInstruction) -> bool {
+    matches!(
+        instruction,
+        ExtendedInstruction::Regular(Instruction::MoveRight)
+            | ExtendedInstruction::Regular(Instruction::MoveLeft)
+            | ExtendedInstruction::JumpRight(_)
+            | ExtendedInstruction::JumpLeft(_)
+    )
+}
+
+/// Helper: number of cells the instruction moves the pointer by, at each step for scans
+fn pointer_move(instruction: &ExtendedInstruction) -> i64 {
+    match instruction {
+        ExtendedInstruction::Regular(Instruction::MoveRight) => 1,
+        ExtendedInstruction::Regular(Instruction::MoveLeft) => -1,
+        ExtendedInstruction::JumpRight(n) | ExtendedInstruction::ScanRight(n) => *n as i64,
+        ExtendedInstruction::JumpLeft(n) | ExtendedInstruction::ScanLeft(n) => -(*n as i64),
+        _ => 0,
+    }
+}
+
+/// Helper: offset (in cells) from the pointer of the cell the instruction operates on
+fn cell_offset(instruction: &ExtendedInstruction) -> i32 {
+    match instruction {
+        ExtendedInstruction::Add { offset, .. }
+        | ExtendedInstruction::Sub { offset, .. }
+        | ExtendedInstruction::SetZero { offset }
+        | ExtendedInstruction::SetValue { offset, .. }
+        | ExtendedInstruction::Output { offset } => *offset,
+        _ => 0,
+    }
+}
+
+/// Helper: write the relative offset from the end of a 4-byte jump offset field to the target index
+fn patch_jump(machine_code: &mut [u8], field_end: usize, target: usize) {
+    let offset = target as i32 - field_end as i32;
+    machine_code[field_end - 4..field_end].copy_from_slice(&offset.to_le_bytes());
+}
+
+// ********************************************************************************************* //
+//                                       HOST CALLBACKS                                          //
+// ********************************************************************************************* //
+
+/// Size of the output buffer used by buffered `.` instructions
+const OUTPUT_BUFFER_SIZE: usize = 4096;
+
+/// Value of `JitContext::fault` when no bounds check failed
+const NO_FAULT: usize = usize::MAX;
+
+/// State passed to the compiled code, and handed back to the I/O callbacks.
+///
+/// The compiled code accesses the first fields directly: their layout must match
+/// `x86_64::buffered_output` and `x86_64::bounds_fault`.
+#[repr(C)]
+struct JitContext<'a> {
+    /// Output buffer filled by the compiled code
+    buffer: *mut u8,
+    /// Number of bytes currently in the output buffer
+    buffer_len: usize,
+    /// Size of the output buffer
+    buffer_capacity: usize,
+    /// Index of the instruction whose bounds check failed, or `NO_FAULT`
+    fault: usize,
+    /// Tape pointer when the bounds check failed
+    pointer: usize,
+
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    eof_behavior: EofBehavior,
+    cell_width: CellWidth,
+
+    /// First I/O error encountered. Once set, further I/O is skipped and `,` behaves as end of input.
+    error: Option<std::io::Error>,
+}
+
+impl JitContext<'_> {
+    /// Write the content of the output buffer to the output stream, and empty it
+    fn flush_buffer(&mut self) {
+        let buffer = unsafe { std::slice::from_raw_parts(self.buffer, self.buffer_len) };
+        self.buffer_len = 0;
+
+        if self.error.is_none() {
+            if let Err(error) = self.output.write_all(buffer) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+/// Callback for buffered `.` instructions: flush the full output buffer
+extern "C" fn jit_flush(context: *mut JitContext) {
+    let context = unsafe { &mut *context };
+    context.flush_buffer();
+}
+
+/// Callback for unbuffered `.` instructions: write and flush the current cell value
+extern "C" fn jit_output(context: *mut JitContext, value: u8) {
+    let context = unsafe { &mut *context };
+
+    if context.error.is_none() {
+        if let Err(error) = context
+            .output
+            .write_all(&[value])
+            .and_then(|_| context.output.flush())
+        {
+            context.error = Some(error);
+        }
+    }
+}
+
+/// Callback for the `,` instruction: read a byte, and return the new value of the current cell
+extern "C" fn jit_input(context: *mut JitContext, current: u64) -> u64 {
+    let context = unsafe { &mut *context };
+    context.flush_buffer();
+
+    let value = match context.error {
+        Some(_) => context.eof_behavior.value(context.cell_width),
+        // Flush pending output so that prompts are visible before blocking on input
+        None => match context
+            .output
+            .flush()
+            .and_then(|_| read_input(context.input, context.eof_behavior, context.cell_width))
+        {
+            Ok(value) => value,
+            Err(error) => {
+                context.error = Some(error);
+                context.eof_behavior.value(context.cell_width)
+            }
+        },
+    };
+
+    value.unwrap_or(current)
+}
@@ -0,0 +1,124 @@
+//! Runtime settings shared by the interpreter and the JIT compiler
+
+use std::str::FromStr;
+
+use crate::error::BfError;
+
+/// What the `,` instruction does to the current cell when the input is exhausted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofBehavior {
+    /// Leave the current cell untouched
+    #[default]
+    Unchanged,
+    /// Store 0 in the current cell
+    Zero,
+    /// Store -1 (255) in the current cell
+    MinusOne,
+}
+
+impl EofBehavior {
+    /// Value to store in a cell of the given width at end of input, if any
+    pub fn value(&self, width: CellWidth) -> Option<u64> {
+        match self {
+            EofBehavior::Unchanged => None,
+            EofBehavior::Zero => Some(0),
+            EofBehavior::MinusOne => Some(width.mask()),
+        }
+    }
+}
+
+impl FromStr for EofBehavior {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchanged" => Ok(EofBehavior::Unchanged),
+            "zero" | "0" => Ok(EofBehavior::Zero),
+            "minus-one" | "-1" | "255" => Ok(EofBehavior::MinusOne),
+            _ => Err("expected one of: unchanged, zero, minus-one"),
+        }
+    }
+}
+
+/// Size of a tape cell. Arithmetic on cells wraps around at this size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl CellWidth {
+    /// Size of a cell in bytes
+    pub fn bytes(&self) -> usize {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+            CellWidth::U64 => 8,
+        }
+    }
+
+    /// Largest cell value, with all bits set
+    pub fn mask(&self) -> u64 {
+        u64::MAX >> (64 - 8 * self.bytes())
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::U8),
+            "16" => Ok(CellWidth::U16),
+            "32" => Ok(CellWidth::U32),
+            "64" => Ok(CellWidth::U64),
+            _ => Err("expected one of: 8, 16, 32, 64"),
+        }
+    }
+}
+
+/// Number of cells a growable tape can grow by: moves further right are out of bounds
+pub const MAX_TAPE_GROWTH: usize = 1 << 26;
+
+/// Shape of the memory tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapeConfig {
+    /// Number of cells
+    pub size: usize,
+    /// Grow the tape when the pointer moves past its right end, by up to [`MAX_TAPE_GROWTH`] cells
+    pub growable: bool,
+    /// Move the pointer to the other end of the tape when it moves past one end.
+    /// Takes precedence over `growable`.
+    pub wrapping: bool,
+}
+
+impl TapeConfig {
+    /// Size of the tape in bytes, with cells of the given width. Fails if it does not fit in a `usize`.
+    pub fn bytes(&self, cell_width: CellWidth) -> Result<usize, BfError> {
+        self.size
+            .checked_mul(cell_width.bytes())
+            .ok_or(BfError::TapeTooLarge)
+    }
+
+    /// Number of cells the tape can hold, after growing if it is growable
+    pub fn max_size(&self) -> usize {
+        match self.growable && !self.wrapping {
+            true => self.size.saturating_add(MAX_TAPE_GROWTH),
+            false => self.size,
+        }
+    }
+}
+
+impl Default for TapeConfig {
+    fn default() -> Self {
+        Self {
+            size: 30_000,
+            growable: false,
+            wrapping: false,
+        }
+    }
+}
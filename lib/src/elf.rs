@@ -0,0 +1,285 @@
+//! ELF64 file writer, for ahead-of-time compilation
+//!
+//! Executables are static and not position independent: they are loaded at [`BASE_ADDRESS`].
+//! The file holds the headers and the machine code in a single read-only executable segment, followed by
+//! a writable segment that takes no space in the file: the tape, in `.bss`.
+//!
+//! Section headers (`.text`, `.bss` and their names) are not needed to run the program,
+//! but they let tools like `objdump` find the code.
+//!
+//! Relocatable object files hold the machine code in `.text`, and a symbol table defining a single global function
+//! at its start. The code needs no relocations: it only holds relative jumps, and gets its tape as argument.
+//! An empty `.note.GNU-stack` section tells the linker that the code does not need an executable stack.
+
+/// Address at which executables are loaded
+pub const BASE_ADDRESS: u64 = 0x400000;
+
+/// Page size, to which segments are aligned
+const PAGE_SIZE: u64 = 0x1000;
+
+/// ELF file type of relocatable object files
+const ET_REL: u16 = 1;
+/// ELF file type of executables
+const ET_EXEC: u16 = 2;
+
+/// Size of the ELF header
+const HEADER_SIZE: usize = 64;
+/// Size of a program header
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// Size of a section header
+const SECTION_HEADER_SIZE: usize = 64;
+/// Size of a symbol table entry
+const SYMBOL_SIZE: usize = 24;
+
+/// Names of the sections, referenced by offset in the section headers
+const SECTION_NAMES: &[u8] = b"\0.text\0.bss\0.shstrtab\0.symtab\0.strtab\0.note.GNU-stack\0";
+const TEXT_NAME: u32 = 1;
+const BSS_NAME: u32 = 7;
+const SHSTRTAB_NAME: u32 = 12;
+const SYMTAB_NAME: u32 = 22;
+const STRTAB_NAME: u32 = 30;
+const NOTE_NAME: u32 = 38;
+
+/// File offset of the machine code in executables, after the ELF header and the two program headers
+const CODE_OFFSET: usize = HEADER_SIZE + 2 * PROGRAM_HEADER_SIZE;
+
+/// Address of the first instruction of an executable
+pub fn entry_address() -> u64 {
+    BASE_ADDRESS + CODE_OFFSET as u64
+}
+
+/// Address of the tape of an executable whose machine code has the given size,
+/// on the first page after the code
+pub fn tape_address(code_size: usize) -> u64 {
+    (entry_address() + code_size as u64).next_multiple_of(PAGE_SIZE)
+}
+
+/// Build a static executable running the given machine code from its first byte,
+/// with a zeroed tape of `tape_size` bytes at [`tape_address`].
+pub fn executable(code: &[u8], tape_size: usize) -> Vec<u8> {
+    let code_end = CODE_OFFSET + code.len();
+    let tape = tape_address(code.len());
+
+    // Section names and headers come after the code
+    let names_offset = code_end;
+    let section_headers_offset = (names_offset + SECTION_NAMES.len()).next_multiple_of(8);
+
+    // Executable file, with 2 program headers and 4 sections, the last one holding their names
+    let mut bytes = header(
+        ET_EXEC,
+        entry_address(),
+        HEADER_SIZE,
+        section_headers_offset,
+        2,
+        4,
+        3,
+    );
+
+    // Code segment: headers and machine code (read, execute)
+    bytes.extend(program_header(5, 0, BASE_ADDRESS, code_end, code_end));
+    // Tape segment: zero-initialized memory (read, write)
+    bytes.extend(program_header(6, 0, tape, 0, tape_size));
+
+    bytes.extend_from_slice(code);
+    bytes.extend_from_slice(SECTION_NAMES);
+    bytes.resize(section_headers_offset, 0);
+
+    bytes.extend([0; SECTION_HEADER_SIZE]);
+    bytes.extend(section_header(SectionHeader {
+        name: TEXT_NAME,
+        kind: 1,  // SHT_PROGBITS
+        flags: 6, // SHF_ALLOC | SHF_EXECINSTR
+        address: entry_address(),
+        offset: CODE_OFFSET,
+        size: code.len(),
+        alignment: 16,
+        ..Default::default()
+    }));
+    bytes.extend(section_header(SectionHeader {
+        name: BSS_NAME,
+        kind: 8,  // SHT_NOBITS
+        flags: 3, // SHF_ALLOC | SHF_WRITE
+        address: tape,
+        offset: code_end,
+        size: tape_size,
+        alignment: PAGE_SIZE,
+        ..Default::default()
+    }));
+    bytes.extend(section_header(SectionHeader {
+        name: SHSTRTAB_NAME,
+        kind: 3, // SHT_STRTAB
+        flags: 0,
+        address: 0,
+        offset: names_offset,
+        size: SECTION_NAMES.len(),
+        alignment: 1,
+        ..Default::default()
+    }));
+
+    bytes
+}
+
+/// Build a relocatable object file defining a global function named `symbol`,
+/// which runs the given machine code from its first byte.
+pub fn object(code: &[u8], symbol: &str) -> Vec<u8> {
+    // Symbol names: only the function
+    let mut symbol_names = vec![0];
+    symbol_names.extend_from_slice(symbol.as_bytes());
+    symbol_names.push(0);
+
+    // The code comes right after the ELF header, followed by the symbols and the names
+    let symbols_offset = (HEADER_SIZE + code.len()).next_multiple_of(8);
+    let symbol_names_offset = symbols_offset + 2 * SYMBOL_SIZE;
+    let names_offset = symbol_names_offset + symbol_names.len();
+    let section_headers_offset = (names_offset + SECTION_NAMES.len()).next_multiple_of(8);
+
+    // Object file, without program headers, with 6 sections
+    let mut bytes = header(ET_REL, 0, 0, section_headers_offset, 0, 6, 4);
+
+    bytes.extend_from_slice(code);
+    bytes.resize(symbols_offset, 0);
+    bytes.extend([0; SYMBOL_SIZE]);
+    bytes.extend(function_symbol(1, 1, code.len()));
+    bytes.extend(symbol_names);
+    bytes.extend_from_slice(SECTION_NAMES);
+    bytes.resize(section_headers_offset, 0);
+
+    bytes.extend([0; SECTION_HEADER_SIZE]);
+    bytes.extend(section_header(SectionHeader {
+        name: TEXT_NAME,
+        kind: 1,  // SHT_PROGBITS
+        flags: 6, // SHF_ALLOC | SHF_EXECINSTR
+        offset: HEADER_SIZE,
+        size: code.len(),
+        alignment: 16,
+        ..Default::default()
+    }));
+    bytes.extend(section_header(SectionHeader {
+        name: SYMTAB_NAME,
+        kind: 2, // SHT_SYMTAB
+        offset: symbols_offset,
+        size: 2 * SYMBOL_SIZE,
+        link: 3, // symbol names in .strtab
+        info: 1, // index of the first global symbol
+        alignment: 8,
+        entry_size: SYMBOL_SIZE,
+        ..Default::default()
+    }));
+    bytes.extend(section_header(SectionHeader {
+        name: STRTAB_NAME,
+        kind: 3, // SHT_STRTAB
+        offset: symbol_names_offset,
+        size: names_offset - symbol_names_offset,
+        alignment: 1,
+        ..Default::default()
+    }));
+    bytes.extend(section_header(SectionHeader {
+        name: SHSTRTAB_NAME,
+        kind: 3, // SHT_STRTAB
+        offset: names_offset,
+        size: SECTION_NAMES.len(),
+        alignment: 1,
+        ..Default::default()
+    }));
+    bytes.extend(section_header(SectionHeader {
+        name: NOTE_NAME,
+        kind: 1, // SHT_PROGBITS
+        offset: section_headers_offset,
+        alignment: 1,
+        ..Default::default()
+    }));
+
+    bytes
+}
+
+// ********************************************************************************************* //
+//                                           HELPER FUNCTIONS                                    //
+// ********************************************************************************************* //
+
+/// Fields of a section header
+#[derive(Default)]
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    alignment: u64,
+    entry_size: usize,
+}
+
+/// Helper: ELF header for x86-64 of the given file type, entry point and header table locations
+fn header(
+    file_type: u16,
+    entry: u64,
+    program_headers_offset: usize,
+    section_headers_offset: usize,
+    program_header_count: u16,
+    section_header_count: u16,
+    names_index: u16,
+) -> Vec<u8> {
+    let mut bytes = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0]; // 64-bit, little endian, version 1, System V
+    bytes.extend([0; 8]); // padding
+    bytes.extend(file_type.to_le_bytes());
+    bytes.extend(0x3eu16.to_le_bytes()); // x86-64
+    bytes.extend(1u32.to_le_bytes()); // version
+    bytes.extend(entry.to_le_bytes());
+    bytes.extend((program_headers_offset as u64).to_le_bytes());
+    bytes.extend((section_headers_offset as u64).to_le_bytes());
+    bytes.extend(0u32.to_le_bytes()); // flags
+    bytes.extend((HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend((PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend(program_header_count.to_le_bytes());
+    bytes.extend((SECTION_HEADER_SIZE as u16).to_le_bytes());
+    bytes.extend(section_header_count.to_le_bytes());
+    bytes.extend(names_index.to_le_bytes());
+    bytes
+}
+
+/// Helper: header of a loadable segment with the given permissions (read 4, write 2, execute 1)
+fn program_header(
+    flags: u32,
+    offset: usize,
+    address: u64,
+    file_size: usize,
+    memory_size: usize,
+) -> Vec<u8> {
+    let mut bytes = 1u32.to_le_bytes().to_vec(); // PT_LOAD
+    bytes.extend(flags.to_le_bytes());
+    bytes.extend((offset as u64).to_le_bytes());
+    bytes.extend(address.to_le_bytes()); // virtual address
+    bytes.extend(address.to_le_bytes()); // physical address
+    bytes.extend((file_size as u64).to_le_bytes());
+    bytes.extend((memory_size as u64).to_le_bytes());
+    bytes.extend(PAGE_SIZE.to_le_bytes()); // alignment
+    bytes
+}
+
+/// Helper: section header
+fn section_header(section: SectionHeader) -> Vec<u8> {
+    let mut bytes = section.name.to_le_bytes().to_vec();
+    bytes.extend(section.kind.to_le_bytes());
+    bytes.extend(section.flags.to_le_bytes());
+    bytes.extend(section.address.to_le_bytes());
+    bytes.extend((section.offset as u64).to_le_bytes());
+    bytes.extend((section.size as u64).to_le_bytes());
+    bytes.extend(section.link.to_le_bytes());
+    bytes.extend(section.info.to_le_bytes());
+    bytes.extend(section.alignment.to_le_bytes());
+    bytes.extend((section.entry_size as u64).to_le_bytes());
+    bytes
+}
+
+/// Helper: symbol table entry of a global function, defined at the start of a section
+fn function_symbol(name: u32, section_index: u16, size: usize) -> Vec<u8> {
+    let mut bytes = name.to_le_bytes().to_vec();
+    bytes.push(0x12); // STB_GLOBAL | STT_FUNC
+    bytes.push(0); // default visibility
+    bytes.extend(section_index.to_le_bytes());
+    bytes.extend(0u64.to_le_bytes()); // value: offset in the section
+    bytes.extend((size as u64).to_le_bytes());
+    bytes
+}
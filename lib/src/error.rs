@@ -0,0 +1,85 @@
+//! Errors reported while compiling or executing brainfuck code
+
+use std::{fmt, io};
+
+use crate::{instructions::Instruction, lexer::Span};
+
+/// An error in a brainfuck program
+#[derive(Debug)]
+pub enum BfError {
+    /// A `[` without its matching `]`
+    UnmatchedOpenBracket(Span),
+    /// A `]` without its matching `[`
+    UnmatchedCloseBracket(Span),
+    /// The tape pointer was moved out of the tape by the instruction at this location
+    TapeOutOfBounds(Span),
+    /// The tape does not fit in memory
+    TapeTooLarge,
+    /// Reading the program input or writing its output failed
+    Io(io::Error),
+}
+
+impl fmt::Display for BfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BfError::UnmatchedOpenBracket(span) => write!(f, "unmatched '[' at {}", span),
+            BfError::UnmatchedCloseBracket(span) => write!(f, "unmatched ']' at {}", span),
+            BfError::TapeOutOfBounds(span) => write!(f, "tape pointer out of bounds at {}", span),
+            BfError::TapeTooLarge => write!(f, "tape too large to allocate"),
+            BfError::Io(error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for BfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BfError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BfError {
+    fn from(error: io::Error) -> Self {
+        BfError::Io(error)
+    }
+}
+
+/// `TapeOutOfBounds` error for the instruction covering `span`, which started with the pointer on cell `pointer`
+/// of a tape of `len` cells.
+///
+/// Optimized instructions stand for several instructions of the source: the error is reported at the first pointer
+/// move of `source` within `span` that leaves the tape, repeating the loop body for scans. It is reported at `span`
+/// if there is none, when the pointer already left the tape before the instruction.
+pub(crate) fn tape_out_of_bounds(
+    source: &[(Instruction, Span)],
+    span: Span,
+    pointer: i64,
+    len: i64,
+) -> BfError {
+    let start = source.partition_point(|(_, token)| token.offset < span.offset);
+    let end = source.partition_point(|(_, token)| token.offset < span.offset + span.len);
+    let tokens = &source[start..end];
+    let scan = matches!(tokens.first(), Some((Instruction::JumpForward, _)));
+
+    let mut position = pointer;
+    while (0..len).contains(&position) {
+        let before = position;
+        for (instruction, token) in tokens {
+            position += match instruction {
+                Instruction::MoveRight => 1,
+                Instruction::MoveLeft => -1,
+                _ => continue,
+            };
+            if !(0..len).contains(&position) {
+                return BfError::TapeOutOfBounds(*token);
+            }
+        }
+        if !scan || position == before {
+            break;
+        }
+    }
+
+    BfError::TapeOutOfBounds(span)
+}
@@ -6,7 +6,7 @@ pub enum Instruction {
     Increment,
     Decrement,
     Output,
-    // Input,
+    Input,
     JumpForward,
     JumpBackwards,
 }
@@ -21,7 +21,7 @@ impl TryFrom<char> for Instruction {
             '+' => Ok(Instruction::Increment),
             '-' => Ok(Instruction::Decrement),
             '.' => Ok(Instruction::Output),
-            // ',' => Ok(Instruction::Input),
+            ',' => Ok(Instruction::Input),
             '[' => Ok(Instruction::JumpForward),
             ']' => Ok(Instruction::JumpBackwards),
             _ => Err("Unknown instruction character"),
@@ -37,6 +37,7 @@ impl From<Instruction> for char {
             Instruction::Increment => '+',
             Instruction::Decrement => '-',
             Instruction::Output => '.',
+            Instruction::Input => ',',
             Instruction::JumpForward => '[',
             Instruction::JumpBackwards => ']',
         }
@@ -49,10 +50,42 @@ impl From<Instruction> for char {
 pub enum ExtendedInstruction {
     Regular(Instruction),
 
-    // Factorized instructions
-    Add(u8),
-    Sub(u8),
+    // Factorized instructions, operating on the cell at `offset` from the current one
+    Add {
+        value: u64,
+        offset: i32,
+    },
+    Sub {
+        value: u64,
+        offset: i32,
+    },
     JumpRight(u32),
     JumpLeft(u32),
-    SetZero,
+    SetZero {
+        offset: i32,
+    },
+    Output {
+        offset: i32,
+    },
+
+    // Constant instructions, for cell values known at compile time
+    /// Set the cell at `offset` from the current one to `value`
+    SetValue {
+        value: u64,
+        offset: i32,
+    },
+    /// Output a byte known at compile time
+    OutputValue(u8),
+
+    // Loop instructions
+    /// Add the current cell multiplied by `factor` to the cell at `offset` from the current one.
+    /// Like the loop it replaces, it only accesses the target cell if the current one is not 0.
+    MulAdd {
+        offset: i32,
+        factor: u64,
+    },
+    /// Move the pointer right by the stride until the current cell is 0
+    ScanRight(u32),
+    /// Move the pointer left by the stride until the current cell is 0
+    ScanLeft(u32),
 }
@@ -1,82 +1,237 @@
 //! Interpreter to run Brainfuck code.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{Read, Stdin, Stdout, Write},
+};
 
 use crate::{
+    config::{CellWidth, EofBehavior, TapeConfig},
+    error::{tape_out_of_bounds, BfError},
     instructions::{ExtendedInstruction, Instruction},
-    optimizer::{
-        instructions_to_extended, optimize_instruction_repetitions, optimize_pattern_based,
-    },
+    io::read_input,
+    ir::Node,
+    lexer::Span,
+    passes::{Pass, PassManager, PassReport},
 };
 
 /// An implementation of a Brainfuck interpreter
-pub struct Interpreter {
+///
+/// `,` reads from `R` and `.` writes to `W`, which default to stdin and stdout.
+pub struct Interpreter<R: Read = Stdin, W: Write = Stdout> {
     /// Stack pointer internal variable
     stack_pointer: usize,
-    /// Stack vector. Is initialized with 30 000 memory cells at 0
-    stack: Vec<u8>,
+    /// Stack vector. Is initialized with `tape.size` memory cells at 0 (30 000 by default),
+    /// allocated on the next execution when the tape is reset
+    stack: Vec<u64>,
+    /// Size and edge behavior of the stack
+    tape: TapeConfig,
+    /// Size of the stack cells, whose values are kept below `cell_width.mask()`
+    cell_width: CellWidth,
 
     /// Hashmap that associates each '[' bracket index with its corresponding ']' bracket index
     forward_jumps: HashMap<usize, usize>,
     /// Hashmap that associates each ']' bracket index with its corresponding '[' bracket index
     backward_jumps: HashMap<usize, usize>,
+
+    /// What `,` stores in the current cell at end of input
+    eof_behavior: EofBehavior,
+
+    /// Optimization passes run before executing
+    passes: PassManager,
+    /// What each optimization pass did during the last execution
+    pass_reports: Vec<PassReport>,
+
+    /// Stream read by the `,` instruction
+    input: R,
+    /// Stream written by the `.` instruction
+    output: W,
 }
 
-#[allow(dead_code)]
 impl Interpreter {
-    /// Build a new interpreter
+    /// Build a new interpreter reading from stdin and writing to stdout
     pub fn new() -> Self {
         Self {
             stack_pointer: 0,
             stack: vec![0; 30_000],
+            tape: TapeConfig::default(),
+            cell_width: CellWidth::default(),
             forward_jumps: HashMap::new(),
             backward_jumps: HashMap::new(),
+            eof_behavior: EofBehavior::default(),
+            passes: PassManager::new(),
+            pass_reports: Vec::new(),
+            input: std::io::stdin(),
+            output: std::io::stdout(),
         }
     }
+}
 
-    /// Execute some brainfuck code from a tokenized program
-    pub fn execute(&mut self, program: &[Instruction]) {
-        let instructions = instructions_to_extended(program);
-        let instructions = optimize_instruction_repetitions(&instructions);
-        let instructions = optimize_pattern_based(&instructions);
+#[allow(dead_code)]
+impl<R: Read, W: Write> Interpreter<R, W> {
+    /// Replace the stream read by the `,` instruction
+    pub fn with_input<I: Read>(self, input: I) -> Interpreter<I, W> {
+        Interpreter {
+            stack_pointer: self.stack_pointer,
+            stack: self.stack,
+            tape: self.tape,
+            cell_width: self.cell_width,
+            forward_jumps: self.forward_jumps,
+            backward_jumps: self.backward_jumps,
+            eof_behavior: self.eof_behavior,
+            passes: self.passes,
+            pass_reports: self.pass_reports,
+            input,
+            output: self.output,
+        }
+    }
 
-        // Do a single forward pass over the whole code in order to match all loop brackets in the hash maps
-        let mut bracket_indices: Vec<usize> = Vec::new(); // Store the encountered forward brackets on a stack
+    /// Replace the stream written by the `.` instruction
+    pub fn with_output<O: Write>(self, output: O) -> Interpreter<R, O> {
+        Interpreter {
+            stack_pointer: self.stack_pointer,
+            stack: self.stack,
+            tape: self.tape,
+            cell_width: self.cell_width,
+            forward_jumps: self.forward_jumps,
+            backward_jumps: self.backward_jumps,
+            eof_behavior: self.eof_behavior,
+            passes: self.passes,
+            pass_reports: self.pass_reports,
+            input: self.input,
+            output,
+        }
+    }
 
-        for (index, instr) in instructions.iter().enumerate() {
-            match instr {
-                ExtendedInstruction::Regular(Instruction::JumpForward) => {
-                    bracket_indices.push(index)
-                }
-                ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
-                    let forward_index = bracket_indices.pop().expect("Unmatched closing bracket");
-                    self.forward_jumps.insert(forward_index, index);
-                    self.backward_jumps.insert(index, forward_index);
-                }
-                _ => {}
-            }
+    /// Stream written by the `.` instruction
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Consume the interpreter and return its output stream
+    pub fn into_output(self) -> W {
+        self.output
+    }
+
+    /// Cells of the tape, as left by the last execution
+    pub fn tape(&self) -> &[u64] {
+        &self.stack
+    }
+
+    /// Set the size and edge behavior of the tape. This resets the tape.
+    pub fn with_tape(mut self, tape: TapeConfig) -> Self {
+        self.tape = tape;
+        self.stack_pointer = 0;
+        self.stack = Vec::new();
+        self
+    }
+
+    /// Set the size of the tape cells. This resets the tape.
+    pub fn with_cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
+        self.stack_pointer = 0;
+        self.stack = Vec::new();
+        self
+    }
+
+    /// Set what `,` stores in the current cell at end of input
+    pub fn with_eof_behavior(mut self, eof_behavior: EofBehavior) -> Self {
+        self.eof_behavior = eof_behavior;
+        self
+    }
+
+    /// Set the optimization passes run before executing
+    pub fn with_passes(mut self, passes: PassManager) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// What each optimization pass did during the last execution, in execution order
+    pub fn pass_reports(&self) -> &[PassReport] {
+        &self.pass_reports
+    }
+
+    /// Execute some brainfuck code from a tokenized program.
+    /// Runs from the tape and pointer left by the previous execution, unless the interpreter was cleared.
+    pub fn execute(&mut self, program: &[(Instruction, Span)]) -> Result<(), BfError> {
+        let fresh = self.stack.is_empty();
+        if fresh {
+            self.stack = zeroed_tape(self.tape.size)?;
+        }
+
+        // Pointer moves are checked one by one, so they are never deferred to cells at an offset
+        let mut passes = self.passes.clone().with_pass(Pass::PointerOffsets, false);
+        // Cell positions tracked by constant propagation would alias when the pointer wraps around
+        if self.tape.wrapping {
+            passes = passes.with_pass(Pass::Constants, false);
         }
+        // Cells are only known to be 0 at the start of the program on a fresh tape, not when continuing
+        // from a previous execution
+        if !fresh {
+            passes = passes
+                .with_pass(Pass::DeadLoops, false)
+                .with_pass(Pass::Constants, false);
+        }
+        let (nodes, reports) = passes.run(program, self.cell_width)?;
+        self.pass_reports = reports;
+
+        // Lower the tree to a flat list of instructions, recording the matching loop brackets in the hash maps
+        let mut instructions = Vec::new();
+        self.lower(&nodes, &mut instructions);
 
-        assert!(
-            bracket_indices.is_empty(),
-            "There exists unmatched opening brackets"
-        );
+        // Locate faults at the pointer move leaving the tape: instructions fail without moving the pointer
+        match self.run(&instructions) {
+            Err(BfError::TapeOutOfBounds(span)) => {
+                let len = self.tape.max_size() as i64;
+                Err(tape_out_of_bounds(
+                    program,
+                    span,
+                    self.stack_pointer as i64,
+                    len,
+                ))
+            }
+            result => result,
+        }
+    }
 
-        // Now, we can execute the program until the instructions run out
+    /// Execute the lowered instructions until they run out
+    fn run(&mut self, instructions: &[(ExtendedInstruction, Span)]) -> Result<(), BfError> {
         let mut instruction_pointer: usize = 0;
         while instruction_pointer < instructions.len() {
-            match instructions[instruction_pointer] {
-                ExtendedInstruction::Regular(Instruction::MoveRight) => self.stack_pointer += 1,
-                ExtendedInstruction::Regular(Instruction::MoveLeft) => self.stack_pointer -= 1,
+            let (instruction, span) = instructions[instruction_pointer];
+            match instruction {
+                ExtendedInstruction::Regular(Instruction::MoveRight) => {
+                    self.move_pointer(1, span)?
+                }
+                ExtendedInstruction::Regular(Instruction::MoveLeft) => {
+                    self.move_pointer(-1, span)?
+                }
                 ExtendedInstruction::Regular(Instruction::Increment) => {
-                    self.stack[self.stack_pointer] = self.stack[self.stack_pointer].wrapping_add(1);
+                    self.add_to_cell(0, 1, span)?
                 }
                 ExtendedInstruction::Regular(Instruction::Decrement) => {
-                    self.stack[self.stack_pointer] = self.stack[self.stack_pointer].wrapping_sub(1)
+                    self.add_to_cell(0, 1u64.wrapping_neg(), span)?
                 }
 
                 ExtendedInstruction::Regular(Instruction::Output) => {
-                    print!("{}", self.stack[self.stack_pointer] as char)
+                    // Write the raw low byte: printing it as a char would UTF-8 encode values above 127
+                    self.output
+                        .write_all(&[self.stack[self.stack_pointer] as u8])?;
+                }
+                ExtendedInstruction::Output { offset } => {
+                    let index = self.cell_index(offset as isize, span)?;
+                    self.output.write_all(&[self.stack[index] as u8])?;
+                }
+                ExtendedInstruction::OutputValue(value) => self.output.write_all(&[value])?,
+                ExtendedInstruction::Regular(Instruction::Input) => {
+                    // Flush pending output so that prompts are visible before blocking on input
+                    self.output.flush()?;
+
+                    if let Some(value) =
+                        read_input(&mut self.input, self.eof_behavior, self.cell_width)?
+                    {
+                        self.stack[self.stack_pointer] = value;
+                    }
                 }
                 ExtendedInstruction::Regular(Instruction::JumpForward) => {
                     if self.stack[self.stack_pointer] == 0 {
@@ -88,31 +243,166 @@ impl Interpreter {
                         instruction_pointer = self.backward_jumps[&instruction_pointer];
                     }
                 }
-                ExtendedInstruction::Add(n) => {
-                    self.stack[self.stack_pointer] = self.stack[self.stack_pointer].wrapping_add(n)
+                ExtendedInstruction::Add { value, offset } => {
+                    self.add_to_cell(offset, value, span)?
+                }
+                ExtendedInstruction::Sub { value, offset } => {
+                    self.add_to_cell(offset, value.wrapping_neg(), span)?
+                }
+                ExtendedInstruction::JumpLeft(n) => self.move_pointer(-(n as isize), span)?,
+                ExtendedInstruction::JumpRight(n) => self.move_pointer(n as isize, span)?,
+                ExtendedInstruction::SetZero { offset } => {
+                    let index = self.cell_index(offset as isize, span)?;
+                    self.stack[index] = 0;
                 }
-                ExtendedInstruction::Sub(n) => {
-                    self.stack[self.stack_pointer] = self.stack[self.stack_pointer].wrapping_sub(n)
+                ExtendedInstruction::SetValue { value, offset } => {
+                    let index = self.cell_index(offset as isize, span)?;
+                    self.stack[index] = value & self.cell_width.mask();
+                }
+                ExtendedInstruction::ScanRight(stride) => self.scan(stride as isize, span)?,
+                ExtendedInstruction::ScanLeft(stride) => self.scan(-(stride as isize), span)?,
+                ExtendedInstruction::MulAdd { offset, factor } => {
+                    let value = self.stack[self.stack_pointer];
+                    if value != 0 {
+                        let index = self.cell_index(offset as isize, span)?;
+                        let product = value.wrapping_mul(factor);
+                        self.stack[index] =
+                            self.stack[index].wrapping_add(product) & self.cell_width.mask();
+                    }
                 }
-                ExtendedInstruction::JumpLeft(n) => self.stack_pointer -= n as usize,
-                ExtendedInstruction::JumpRight(n) => self.stack_pointer += n as usize,
-                ExtendedInstruction::SetZero => self.stack[self.stack_pointer] = 0,
             }
 
             // Go to the next instruction
             instruction_pointer += 1;
         }
+
+        self.output.flush()?;
+
+        Ok(())
+    }
+
+    /// Append the instructions of the given nodes, with the index of each loop bracket in the jump hash maps
+    fn lower(&mut self, nodes: &[Node], instructions: &mut Vec<(ExtendedInstruction, Span)>) {
+        for node in nodes {
+            match node {
+                Node::Instruction(instruction, span) => instructions.push((*instruction, *span)),
+                Node::Loop { body, open, close } => {
+                    let forward_index = instructions.len();
+                    instructions.push((
+                        ExtendedInstruction::Regular(Instruction::JumpForward),
+                        *open,
+                    ));
+                    self.lower(body, instructions);
+
+                    let backward_index = instructions.len();
+                    instructions.push((
+                        ExtendedInstruction::Regular(Instruction::JumpBackwards),
+                        *close,
+                    ));
+
+                    self.forward_jumps.insert(forward_index, backward_index);
+                    self.backward_jumps.insert(backward_index, forward_index);
+                }
+            }
+        }
+    }
+
+    /// Add a value to the cell at the given offset from the stack pointer, wrapping around at the cell width
+    fn add_to_cell(&mut self, offset: i32, value: u64, span: Span) -> Result<(), BfError> {
+        let cell = match offset {
+            0 => &mut self.stack[self.stack_pointer],
+            _ => {
+                let index = self.cell_index(offset as isize, span)?;
+                &mut self.stack[index]
+            }
+        };
+        *cell = cell.wrapping_add(value) & self.cell_width.mask();
+        Ok(())
+    }
+
+    /// Move the stack pointer by the given stride until the current cell is 0
+    fn scan(&mut self, stride: isize, span: Span) -> Result<(), BfError> {
+        // Search the tape in one go, then fall back to single moves to handle its edges
+        let found = match stride {
+            1.. => self.stack[self.stack_pointer..]
+                .iter()
+                .step_by(stride as usize)
+                .position(|cell| *cell == 0)
+                .map(|position| self.stack_pointer + position * stride as usize),
+            _ => self.stack[..=self.stack_pointer]
+                .iter()
+                .rev()
+                .step_by(stride.unsigned_abs())
+                .position(|cell| *cell == 0)
+                .map(|position| self.stack_pointer - position * stride.unsigned_abs()),
+        };
+        if let Some(pointer) = found {
+            self.stack_pointer = pointer;
+            return Ok(());
+        }
+
+        while self.stack[self.stack_pointer] != 0 {
+            self.move_pointer(stride, span)?;
+        }
+        Ok(())
+    }
+
+    /// Move the stack pointer by the given offset, wrapping around or growing the tape if configured.
+    /// Fails without moving if the pointer would leave the tape.
+    fn move_pointer(&mut self, offset: isize, span: Span) -> Result<(), BfError> {
+        self.stack_pointer = self.cell_index(offset, span)?;
+        Ok(())
+    }
+
+    /// Index of the cell at the given offset from the stack pointer, wrapping around or growing the tape if configured.
+    /// Fails if the cell is out of the tape.
+    fn cell_index(&mut self, offset: isize, span: Span) -> Result<usize, BfError> {
+        let len = self.stack.len();
+
+        if self.tape.wrapping {
+            let offset = offset.rem_euclid(len as isize) as usize;
+            return Ok((self.stack_pointer + offset) % len);
+        }
+
+        let index = self
+            .stack_pointer
+            .checked_add_signed(offset)
+            .ok_or(BfError::TapeOutOfBounds(span))?;
+
+        if index >= len {
+            let max_len = self.tape.max_size();
+            if index >= max_len {
+                return Err(BfError::TapeOutOfBounds(span));
+            }
+            // Grow geometrically to amortize repeated moves to the right
+            let new_len = (index + 1).max(2 * len).min(max_len);
+            self.stack
+                .try_reserve_exact(new_len - len)
+                .map_err(|_| BfError::TapeTooLarge)?;
+            self.stack.resize(new_len, 0);
+        }
+
+        Ok(index)
     }
 
     /// Clear the interpreter state from its previous execution
     pub fn clear(&mut self) {
         self.stack_pointer = 0;
-        self.stack = vec![0; 30_000];
+        self.stack = Vec::new();
         self.backward_jumps.clear();
         self.forward_jumps.clear();
     }
 }
 
+/// Allocate a tape of `size` cells at 0, failing instead of aborting if it does not fit in memory
+fn zeroed_tape(size: usize) -> Result<Vec<u64>, BfError> {
+    let mut tape = Vec::new();
+    tape.try_reserve_exact(size)
+        .map_err(|_| BfError::TapeTooLarge)?;
+    tape.resize(size, 0);
+    Ok(tape)
+}
+
 // Implement the Default trait
 impl Default for Interpreter {
     fn default() -> Self {
@@ -0,0 +1,24 @@
+//! Input / output helpers shared by the interpreter and the JIT compiler
+
+use std::io::{ErrorKind, Read, Result};
+
+use crate::config::{CellWidth, EofBehavior};
+
+/// Read a single byte for the `,` instruction, retrying on interruption.
+/// Returns the value to store in the current cell, or `None` if it must be left unchanged.
+pub(crate) fn read_input<R: Read + ?Sized>(
+    input: &mut R,
+    eof_behavior: EofBehavior,
+    width: CellWidth,
+) -> Result<Option<u64>> {
+    let mut byte = [0u8; 1];
+
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(eof_behavior.value(width)),
+            Ok(_) => return Ok(Some(byte[0] as u64)),
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Structured representation of brainfuck programs
+//!
+//! Loops are nodes holding their body, so that passes working on whole loops do not need to match brackets.
+//! Both backends lower this tree: the interpreter into a flat list of instructions with jump tables,
+//! and the JIT compiler directly into machine code.
+
+use crate::{
+    error::BfError,
+    instructions::{ExtendedInstruction, Instruction},
+    lexer::Span,
+    optimizer::instructions_to_extended,
+};
+
+/// A node of the program tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// A single instruction, which is never a bracket
+    Instruction(ExtendedInstruction, Span),
+    /// A loop, with the spans of its brackets
+    Loop {
+        body: Vec<Node>,
+        open: Span,
+        close: Span,
+    },
+}
+
+impl Node {
+    /// Span of the source code this node was generated from
+    pub fn span(&self) -> Span {
+        match self {
+            Node::Instruction(_, span) => *span,
+            Node::Loop { open, close, .. } => open.merge(close),
+        }
+    }
+}
+
+/// Build the program tree from a tokenized program
+pub fn parse(program: &[(Instruction, Span)]) -> Result<Vec<Node>, BfError> {
+    build_tree(&instructions_to_extended(program))
+}
+
+/// Build the program tree from extended instructions, matching the brackets.
+/// Reports the first unmatched `]`, or else the first unmatched `[`.
+pub fn build_tree(instructions: &[(ExtendedInstruction, Span)]) -> Result<Vec<Node>, BfError> {
+    // Nodes of the enclosing loops being built, with the span of their opening bracket
+    let mut parents: Vec<(Vec<Node>, Span)> = Vec::new();
+    let mut nodes: Vec<Node> = Vec::new();
+
+    for (instruction, span) in instructions {
+        match instruction {
+            ExtendedInstruction::Regular(Instruction::JumpForward) => {
+                parents.push((std::mem::take(&mut nodes), *span));
+            }
+            ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
+                let (parent, open) = parents.pop().ok_or(BfError::UnmatchedCloseBracket(*span))?;
+                let body = std::mem::replace(&mut nodes, parent);
+                nodes.push(Node::Loop {
+                    body,
+                    open,
+                    close: *span,
+                });
+            }
+            _ => nodes.push(Node::Instruction(*instruction, *span)),
+        }
+    }
+
+    match parents.first() {
+        Some((_, open)) => Err(BfError::UnmatchedOpenBracket(*open)),
+        None => Ok(nodes),
+    }
+}
+
+/// Convert the program tree back to extended instructions, with brackets around loop bodies
+pub fn flatten(nodes: &[Node]) -> Vec<(ExtendedInstruction, Span)> {
+    let mut instructions = Vec::new();
+    push_nodes(&mut instructions, nodes);
+    instructions
+}
+
+// ********************************************************************************************* //
+//                                           HELPER FUNCTIONS                                    //
+// ********************************************************************************************* //
+
+/// Helper: pushes the instructions of the given nodes inside the given buffer
+fn push_nodes(buffer: &mut Vec<(ExtendedInstruction, Span)>, nodes: &[Node]) {
+    for node in nodes {
+        match node {
+            Node::Instruction(instruction, span) => buffer.push((*instruction, *span)),
+            Node::Loop { body, open, close } => {
+                buffer.push((
+                    ExtendedInstruction::Regular(Instruction::JumpForward),
+                    *open,
+                ));
+                push_nodes(buffer, body);
+                buffer.push((
+                    ExtendedInstruction::Regular(Instruction::JumpBackwards),
+                    *close,
+                ));
+            }
+        }
+    }
+}
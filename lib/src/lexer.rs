@@ -1,18 +1,85 @@
 //! Simple lexer utils that help convert a byte stream into a brainfuck Instruction stream.
 
+use std::fmt;
+
 use crate::instructions::Instruction;
 
+/// Location of an instruction in the source code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    /// Byte offset from the start of the source
+    pub offset: usize,
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column number (in bytes), starting at 1
+    pub column: usize,
+    /// Length in bytes of the covered source code
+    pub len: usize,
+}
+
+impl Span {
+    /// Span covering both this span and a later one
+    pub fn merge(&self, other: &Span) -> Span {
+        Span {
+            len: other.offset + other.len - self.offset,
+            ..*self
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {} (byte {})",
+            self.line, self.column, self.offset
+        )
+    }
+}
+
 /// Convert an iterator over bytes into an iterator over brainfuck Instructions
 pub fn tokenize<I: IntoIterator<Item = u8>>(bytes: I) -> impl Iterator<Item = Instruction> {
     bytes
         .into_iter()
-        .filter_map(|c| match Instruction::try_from(c as char) {
-            Ok(i) => Some(i),
-            _ => None,
-        })
+        .filter_map(|c| Instruction::try_from(c as char).ok())
 }
 
 /// Convert an iterator over bytes into a collected Vec<Instruction>
 pub fn tokenize_all<I: IntoIterator<Item = u8>>(bytes: I) -> Vec<Instruction> {
     tokenize(bytes).collect()
 }
+
+/// Convert an iterator over bytes into an iterator over brainfuck Instructions,
+/// along with their location in the source code
+pub fn tokenize_with_spans<I: IntoIterator<Item = u8>>(
+    bytes: I,
+) -> impl Iterator<Item = (Instruction, Span)> {
+    let mut line = 1;
+    let mut column = 0;
+
+    bytes
+        .into_iter()
+        .enumerate()
+        .filter_map(move |(offset, c)| {
+            // Update the position before filtering, so that comments are taken into account
+            if c == b'\n' {
+                line += 1;
+                column = 0;
+                return None;
+            }
+            column += 1;
+
+            let span = Span {
+                offset,
+                line,
+                column,
+                len: 1,
+            };
+            Instruction::try_from(c as char).ok().map(|i| (i, span))
+        })
+}
+
+/// Convert an iterator over bytes into a collected Vec<(Instruction, Span)>
+pub fn tokenize_all_with_spans<I: IntoIterator<Item = u8>>(bytes: I) -> Vec<(Instruction, Span)> {
+    tokenize_with_spans(bytes).collect()
+}
@@ -1,6 +1,16 @@
 pub mod compiler;
+pub mod config;
+pub mod elf;
+pub mod error;
 pub mod instructions;
 pub mod interpreter;
+mod io;
+pub mod ir;
 pub mod lexer;
 pub mod optimizer;
+pub mod passes;
+pub mod rewrite;
+mod tape;
+pub mod transpiler;
+pub mod wasm;
 pub mod x86_64;
@@ -1,26 +1,41 @@
 //! Code optimization during compilation
+//!
+//! Every instruction is paired with the [`Span`] of the source code it was generated from.
+//! When several instructions are merged into one, the resulting instruction covers all of their spans.
 
-use crate::instructions::{ExtendedInstruction, Instruction};
+use std::collections::HashMap;
+
+use crate::{
+    config::CellWidth,
+    instructions::{ExtendedInstruction, Instruction},
+    ir::Node,
+    lexer::Span,
+};
 
 /// Convert regular brainfuck instructions to extended instructions for further processing
-pub fn instructions_to_extended(instructions: &[Instruction]) -> Vec<ExtendedInstruction> {
+pub fn instructions_to_extended(
+    instructions: &[(Instruction, Span)],
+) -> Vec<(ExtendedInstruction, Span)> {
     instructions
         .iter()
-        .map(|i| ExtendedInstruction::Regular(*i))
+        .map(|(i, span)| (ExtendedInstruction::Regular(*i), *span))
         .collect()
 }
 
-/// Optimize instruction repetitions by aggregating them using extended instructions
+/// Optimize instruction repetitions by aggregating them using extended instructions.
+/// Mixed runs like `++-` are folded into their net effect, and `+` and `-` counts wrap around at the cell width.
 pub fn optimize_instruction_repetitions(
-    instructions: &[ExtendedInstruction],
-) -> Vec<ExtendedInstruction> {
+    instructions: &[(ExtendedInstruction, Span)],
+    width: CellWidth,
+) -> Vec<(ExtendedInstruction, Span)> {
     let mut output = Vec::new();
 
     // Flags and counters to identify repeated instructions
     let mut current_instruction: Option<ExtendedInstruction> = None;
-    let mut instruction_count: i32 = 0; // Count consecutive instructions to be grouped (arithmetic !)
+    let mut current_span = Span::default(); // Span covering the grouped instructions
+    let mut instruction_count: i64 = 0; // Count consecutive instructions to be grouped (arithmetic !)
 
-    for instruction in instructions {
+    for (instruction, span) in instructions {
         // Check if we changed instructions
         match (instruction, current_instruction) {
             // Update the instruction count for Increment / Decrement instructions
@@ -54,8 +69,11 @@ pub fn optimize_instruction_repetitions(
                 push_optimized_repeat_instruction(
                     &mut output,
                     &current_instruction,
+                    current_span,
                     instruction_count,
+                    width,
                 );
+                current_span = *span;
 
                 // Reset instruction count with the correct count depending on the conventions
                 match instruction {
@@ -67,115 +85,591 @@ pub fn optimize_instruction_repetitions(
         }
         // Update current instruction
         current_instruction = Some(*instruction);
+        current_span = current_span.merge(span);
     }
 
     // Flush the buffer for the last instruction
-    push_optimized_repeat_instruction(&mut output, &current_instruction, instruction_count);
+    push_optimized_repeat_instruction(
+        &mut output,
+        &current_instruction,
+        current_span,
+        instruction_count,
+        width,
+    );
+
+    output
+}
+
+/// Replace the loops that only move the pointer with scans
+/// Example: `[>]` will be replaced by ScanRight(1), and `[<<<]` by ScanLeft(3)
+pub fn optimize_scan_loops(nodes: &[Node]) -> Vec<Node> {
+    nodes
+        .iter()
+        .map(|node| match node {
+            Node::Loop { body, open, close } => {
+                let scan = match body.as_slice() {
+                    [Node::Instruction(instruction, _)] => match instruction {
+                        ExtendedInstruction::Regular(Instruction::MoveRight) => {
+                            Some(ExtendedInstruction::ScanRight(1))
+                        }
+                        ExtendedInstruction::Regular(Instruction::MoveLeft) => {
+                            Some(ExtendedInstruction::ScanLeft(1))
+                        }
+                        ExtendedInstruction::JumpRight(n) => {
+                            Some(ExtendedInstruction::ScanRight(*n))
+                        }
+                        ExtendedInstruction::JumpLeft(n) => Some(ExtendedInstruction::ScanLeft(*n)),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+
+                match scan {
+                    Some(scan) => Node::Instruction(scan, node.span()),
+                    None => Node::Loop {
+                        body: optimize_scan_loops(body),
+                        open: *open,
+                        close: *close,
+                    },
+                }
+            }
+            _ => node.clone(),
+        })
+        .collect()
+}
+
+/// Replace balanced loops that only add constants to cells with multiplications.
+/// Example: `[->+>++<<]` will be replaced by `MulAdd { offset: 1, factor: 1 }`, `MulAdd { offset: 2, factor: 2 }`, `SetZero`
+///
+/// The loop must move the pointer back to where it started, and add 1 or -1 to the current cell at each iteration.
+pub fn optimize_multiply_loops(nodes: &[Node], width: CellWidth) -> Vec<Node> {
+    let mut output = Vec::new();
+
+    for node in nodes {
+        match node {
+            Node::Loop { body, open, close } => match multiply_loop(body, node.span(), width) {
+                Some(replacement) => output.extend(replacement),
+                None => output.push(Node::Loop {
+                    body: optimize_multiply_loops(body, width),
+                    open: *open,
+                    close: *close,
+                }),
+            },
+            _ => output.push(node.clone()),
+        }
+    }
 
     output
 }
 
-// Optimization patterns, listed in DECREASING PRIORITY ORDER
-static PATTERNS: &[(&[ExtendedInstruction], ExtendedInstruction)] = &[
-    // Set zero pattern: [-]
-    (
-        &[
-            ExtendedInstruction::Regular(Instruction::JumpForward),
-            ExtendedInstruction::Regular(Instruction::Decrement),
-            ExtendedInstruction::Regular(Instruction::JumpBackwards),
-        ],
-        ExtendedInstruction::SetZero,
-    ),
-    // TODO : put other patterns here
-];
+/// Largest pointer offset (in cells) carried by an instruction, so that it fits in a 32-bit displacement in bytes
+const MAX_OFFSET: i64 = 1 << 24;
 
-/// Optimize the given instructions by recognizing patterns and replacing them with more efficient instructions
-/// Example: `[-]` will be replaced by SetZero
-pub fn optimize_pattern_based(instructions: &[ExtendedInstruction]) -> Vec<ExtendedInstruction> {
-    let mut output = instructions.to_vec();
-    let mut optimized_output = Vec::new();
+/// Remove the pointer moves inside straight-line blocks, by operating on cells at an offset from the pointer.
+/// The pointer is moved once at the end of each block, before any loop or input.
+/// Example: `>>+<-` will be replaced by `Add { value: 1, offset: 2 }`, `Sub { value: 1, offset: 1 }`, `>`
+pub fn optimize_pointer_offsets(nodes: &[Node]) -> Vec<Node> {
+    let mut output = Vec::new();
 
-    // For each optimization pattern, do a single pass through the instructions and replace them
-    for (pattern, replacement) in PATTERNS {
-        let mut matching_size = 0;
+    // Virtual pointer offset since the start of the block, and span of the moves that built it
+    let mut offset: i64 = 0;
+    let mut moves_span: Option<Span> = None;
 
-        // While there is still some output to process
-        for (index, instruction) in output.iter().enumerate() {
-            // MATCHING DETECTION
-            // Check if the current instruction matches the pattern
-            if *instruction == pattern[matching_size] {
-                matching_size += 1;
-            } else {
-                // If the pattern doesn't match, we reset the matching size and move to the next instruction
-                optimized_output.extend_from_slice(&output[index - matching_size..index + 1]);
-                matching_size = 0;
+    for node in nodes {
+        let (instruction, span) = match node {
+            Node::Instruction(instruction, span) => (instruction, span),
+            Node::Loop { body, open, close } => {
+                push_pointer_move(&mut output, offset, moves_span);
+                (offset, moves_span) = (0, None);
+                output.push(Node::Loop {
+                    body: optimize_pointer_offsets(body),
+                    open: *open,
+                    close: *close,
+                });
+                continue;
             }
+        };
 
-            // MATCHING PROCESSING
-            // If we matched the whole pattern, we replace it with the optimized instruction
-            if matching_size == pattern.len() {
-                optimized_output.push(*replacement);
+        let moved = match instruction {
+            ExtendedInstruction::Regular(Instruction::MoveRight) => Some(offset + 1),
+            ExtendedInstruction::Regular(Instruction::MoveLeft) => Some(offset - 1),
+            ExtendedInstruction::JumpRight(n) => Some(offset + *n as i64),
+            ExtendedInstruction::JumpLeft(n) => Some(offset - *n as i64),
+            _ => None,
+        };
 
-                // Reset the counters
-                matching_size = 0;
+        match moved {
+            Some(moved) if moved.abs() <= MAX_OFFSET => {
+                offset = moved;
+                moves_span = Some(moves_span.map_or(*span, |first| first.merge(span)));
+                continue;
+            }
+            Some(_) => {
+                // Too far: end the block here
+                push_pointer_move(&mut output, offset, moves_span);
+                (offset, moves_span) = (0, None);
+                output.push(node.clone());
+                continue;
             }
+            None => {}
         }
 
-        // If the matching size is not zero, we need to flush the buffer
-        optimized_output.extend_from_slice(&output[output.len() - matching_size..]);
-
-        // Swap the buffers
-        output = optimized_output;
-        optimized_output = Vec::new();
+        match shift_instruction(instruction, offset as i32) {
+            // The cell at an offset is out of the tape if one of the moves left it: they are part of the span
+            Some(shifted) if offset != 0 => {
+                let moves_span = moves_span.expect("moves led to the offset");
+                output.push(Node::Instruction(shifted, moves_span.merge(span)))
+            }
+            Some(shifted) => output.push(Node::Instruction(shifted, *span)),
+            None => {
+                // Input and other instructions relying on the pointer end the block
+                push_pointer_move(&mut output, offset, moves_span);
+                (offset, moves_span) = (0, None);
+                output.push(node.clone());
+            }
+        }
     }
 
+    push_pointer_move(&mut output, offset, moves_span);
+
     output
 }
 
+/// Remove the loops that can never run, because the current cell is known to be 0 when they are reached:
+/// at the start of the program, right after another loop, or after the current cell was set to 0.
+/// Example: `[-][comment]` will be replaced by SetZero
+pub fn optimize_dead_loops(nodes: &[Node]) -> Vec<Node> {
+    // All cells are 0 at the start of the program
+    remove_dead_loops(nodes, true)
+}
+
+/// Propagate the cell values known at compile time through straight-line code.
+/// All cells are 0 at the start of the program, and the current cell is 0 after a loop or a scan.
+/// Arithmetic on known cells becomes `SetValue`, consecutive sets of a cell are merged,
+/// and `.` on a known cell outputs its value directly.
+/// Example: `[-]+++.` will be replaced by `SetValue { value: 3, offset: 0 }`, `OutputValue(3)`
+///
+/// Cell positions are tracked from the pointer moves, so this is only correct if the pointer does not wrap around.
+pub fn optimize_constants(nodes: &[Node], width: CellWidth) -> Vec<Node> {
+    propagate_constants(nodes, KnownCells::start(), width)
+}
+
 // ********************************************************************************************* //
 //                                           HELPER FUNCTIONS                                    //
 // ********************************************************************************************* //
 
-/// Helper: pushes the optimized repeated instruction corresponding to the input and count inside the given buffer
+/// Helper: pushes the optimized repeated instruction corresponding to the input and count inside the given buffer.
+/// The count is the net number of `+` (or `>`) in the run, negative for `-` (or `<`): net-zero runs push nothing.
 fn push_optimized_repeat_instruction(
-    buffer: &mut Vec<ExtendedInstruction>,
+    buffer: &mut Vec<(ExtendedInstruction, Span)>,
     instruction: &Option<ExtendedInstruction>,
-    instruction_count: i32,
+    span: Span,
+    instruction_count: i64,
+    width: CellWidth,
 ) {
-    match (instruction, instruction_count) {
-        (
-            Some(ExtendedInstruction::Regular(Instruction::Increment))
-            | Some(ExtendedInstruction::Regular(Instruction::Decrement)),
-            instruction_count,
-        ) if instruction_count > 1 => {
-            buffer.push(ExtendedInstruction::Add(instruction_count as u8));
-        }
-        (
-            Some(ExtendedInstruction::Regular(Instruction::Increment))
-            | Some(ExtendedInstruction::Regular(Instruction::Decrement)),
-            instruction_count,
-        ) if instruction_count < -1 => {
-            buffer.push(ExtendedInstruction::Sub(-instruction_count as u8));
-        }
-        (
-            Some(ExtendedInstruction::Regular(Instruction::MoveRight))
-            | Some(ExtendedInstruction::Regular(Instruction::MoveLeft)),
-            instruction_count,
-        ) if instruction_count > 1 => {
-            buffer.push(ExtendedInstruction::JumpRight(instruction_count as u32));
-        }
-        (
-            Some(ExtendedInstruction::Regular(Instruction::MoveRight))
-            | Some(ExtendedInstruction::Regular(Instruction::MoveLeft)),
-            instruction_count,
-        ) if instruction_count < -1 => {
-            buffer.push(ExtendedInstruction::JumpLeft(-instruction_count as u32));
-        }
-        _ => {
-            // By default : we just add the current instruction to the output "as is"
-            if let Some(instruction) = instruction {
-                buffer.push(*instruction);
+    let optimized = match instruction {
+        Some(ExtendedInstruction::Regular(Instruction::Increment))
+        | Some(ExtendedInstruction::Regular(Instruction::Decrement)) => {
+            // Only the count modulo the cell size matters
+            let value = instruction_count.unsigned_abs() & width.mask();
+            match (instruction_count > 0, value) {
+                (_, 0) => None,
+                (true, 1) => Some(ExtendedInstruction::Regular(Instruction::Increment)),
+                (false, 1) => Some(ExtendedInstruction::Regular(Instruction::Decrement)),
+                (true, value) => Some(ExtendedInstruction::Add { value, offset: 0 }),
+                (false, value) => Some(ExtendedInstruction::Sub { value, offset: 0 }),
+            }
+        }
+        Some(ExtendedInstruction::Regular(Instruction::MoveRight))
+        | Some(ExtendedInstruction::Regular(Instruction::MoveLeft)) => match instruction_count {
+            0 => None,
+            1 => Some(ExtendedInstruction::Regular(Instruction::MoveRight)),
+            -1 => Some(ExtendedInstruction::Regular(Instruction::MoveLeft)),
+            2.. => Some(ExtendedInstruction::JumpRight(instruction_count as u32)),
+            _ => Some(ExtendedInstruction::JumpLeft(
+                instruction_count.unsigned_abs() as u32,
+            )),
+        },
+        // By default : we just add the current instruction to the output "as is"
+        _ => *instruction,
+    };
+
+    if let Some(optimized) = optimized {
+        buffer.push((optimized, span));
+    }
+}
+
+/// Helper: replacement of a multiply loop, given its body and span, or `None` if it is not a multiply loop
+fn multiply_loop(body: &[Node], span: Span, width: CellWidth) -> Option<Vec<Node>> {
+    // Pointer offset from the loop start, and value added to each visited cell at each iteration
+    let mut offset: i64 = 0;
+    let mut deltas: Vec<(i64, u64)> = Vec::new();
+
+    for node in body {
+        // Nested loops
+        let Node::Instruction(instruction, _) = node else {
+            return None;
+        };
+
+        let delta = match instruction {
+            ExtendedInstruction::Regular(Instruction::MoveRight) => {
+                offset += 1;
+                continue;
             }
+            ExtendedInstruction::Regular(Instruction::MoveLeft) => {
+                offset -= 1;
+                continue;
+            }
+            ExtendedInstruction::JumpRight(n) => {
+                offset += *n as i64;
+                continue;
+            }
+            ExtendedInstruction::JumpLeft(n) => {
+                offset -= *n as i64;
+                continue;
+            }
+            ExtendedInstruction::Regular(Instruction::Increment) => 1,
+            ExtendedInstruction::Regular(Instruction::Decrement) => width.mask(),
+            ExtendedInstruction::Add { value, offset: 0 } => *value,
+            ExtendedInstruction::Sub { value, offset: 0 } => value.wrapping_neg(),
+            // I/O and other loops
+            _ => return None,
+        };
+
+        match deltas.iter_mut().find(|(cell, _)| *cell == offset) {
+            Some((_, total)) => *total = total.wrapping_add(delta) & width.mask(),
+            None => deltas.push((offset, delta & width.mask())),
+        }
+    }
+
+    // Unbalanced loops
+    if offset != 0 {
+        return None;
+    }
+
+    multiply_loop_replacement(&deltas, span, width)
+}
+
+/// Helper: `MulAdd` instructions and the final `SetZero` of a multiply loop, given the value added to each cell per iteration
+fn multiply_loop_replacement(
+    deltas: &[(i64, u64)],
+    span: Span,
+    width: CellWidth,
+) -> Option<Vec<Node>> {
+    // The loop runs `value` times if it decrements the current cell, and `-value` times if it increments it
+    let negate = match deltas.iter().find(|(cell, _)| *cell == 0) {
+        Some((_, delta)) if *delta == width.mask() => false,
+        Some((_, 1)) => true,
+        _ => return None,
+    };
+
+    let mut replacement = Vec::new();
+    for (offset, delta) in deltas {
+        if *offset == 0 || *delta == 0 {
+            continue;
         }
+        let factor = match negate {
+            true => delta.wrapping_neg() & width.mask(),
+            false => *delta,
+        };
+        let offset = i32::try_from(*offset).ok()?;
+        replacement.push(Node::Instruction(
+            ExtendedInstruction::MulAdd { offset, factor },
+            span,
+        ));
+    }
+    replacement.push(Node::Instruction(
+        ExtendedInstruction::SetZero { offset: 0 },
+        span,
+    ));
+
+    Some(replacement)
+}
+
+/// Helper: the instruction operating on the cell at `offset` from the current one, instead of the current cell.
+/// Returns `None` for instructions that cannot carry an offset.
+fn shift_instruction(
+    instruction: &ExtendedInstruction,
+    offset: i32,
+) -> Option<ExtendedInstruction> {
+    if offset == 0 {
+        return match instruction {
+            ExtendedInstruction::Regular(Instruction::JumpForward)
+            | ExtendedInstruction::Regular(Instruction::JumpBackwards)
+            | ExtendedInstruction::Regular(Instruction::Input)
+            | ExtendedInstruction::MulAdd { .. }
+            | ExtendedInstruction::ScanRight(_)
+            | ExtendedInstruction::ScanLeft(_) => None,
+            _ => Some(*instruction),
+        };
     }
+
+    let shifted = match instruction {
+        ExtendedInstruction::Regular(Instruction::Increment) => {
+            ExtendedInstruction::Add { value: 1, offset }
+        }
+        ExtendedInstruction::Regular(Instruction::Decrement) => {
+            ExtendedInstruction::Sub { value: 1, offset }
+        }
+        ExtendedInstruction::Regular(Instruction::Output) => ExtendedInstruction::Output { offset },
+        ExtendedInstruction::Add { value, offset: 0 } => ExtendedInstruction::Add {
+            value: *value,
+            offset,
+        },
+        ExtendedInstruction::Sub { value, offset: 0 } => ExtendedInstruction::Sub {
+            value: *value,
+            offset,
+        },
+        ExtendedInstruction::SetZero { offset: 0 } => ExtendedInstruction::SetZero { offset },
+        ExtendedInstruction::SetValue { value, offset: 0 } => ExtendedInstruction::SetValue {
+            value: *value,
+            offset,
+        },
+        ExtendedInstruction::Output { offset: 0 } => ExtendedInstruction::Output { offset },
+        ExtendedInstruction::OutputValue(value) => ExtendedInstruction::OutputValue(*value),
+        _ => return None,
+    };
+
+    Some(shifted)
+}
+
+/// Helper: pushes the pointer move by the given offset inside the given buffer, if any
+fn push_pointer_move(buffer: &mut Vec<Node>, offset: i64, span: Option<Span>) {
+    let instruction = match offset {
+        0 => return,
+        1 => ExtendedInstruction::Regular(Instruction::MoveRight),
+        -1 => ExtendedInstruction::Regular(Instruction::MoveLeft),
+        2.. => ExtendedInstruction::JumpRight(offset as u32),
+        _ => ExtendedInstruction::JumpLeft(-offset as u32),
+    };
+    buffer.push(Node::Instruction(instruction, span.unwrap_or_default()));
+}
+
+/// Helper: removes the dead loops among the given nodes, knowing whether the current cell is 0 before them
+fn remove_dead_loops(nodes: &[Node], mut zero: bool) -> Vec<Node> {
+    let mut output = Vec::new();
+
+    for node in nodes {
+        match node {
+            // The loop is skipped, and the current cell is still 0 after it
+            Node::Loop { .. } if zero => {}
+            Node::Loop { body, open, close } => {
+                // The current cell is not 0 when entering the body, and is 0 when leaving the loop
+                output.push(Node::Loop {
+                    body: remove_dead_loops(body, false),
+                    open: *open,
+                    close: *close,
+                });
+                zero = true;
+            }
+            Node::Instruction(instruction, _) => {
+                zero = match instruction {
+                    ExtendedInstruction::SetZero { offset: 0 }
+                    | ExtendedInstruction::ScanRight(_)
+                    | ExtendedInstruction::ScanLeft(_) => true,
+                    // Instructions leaving the current cell and the pointer untouched
+                    ExtendedInstruction::Regular(Instruction::Output)
+                    | ExtendedInstruction::Output { .. }
+                    | ExtendedInstruction::OutputValue(_)
+                    | ExtendedInstruction::MulAdd { .. } => zero,
+                    ExtendedInstruction::Add { offset, .. }
+                    | ExtendedInstruction::Sub { offset, .. }
+                    | ExtendedInstruction::SetZero { offset }
+                    | ExtendedInstruction::SetValue { offset, .. } => zero && *offset != 0,
+                    _ => false,
+                };
+                output.push(node.clone());
+            }
+        }
+    }
+
+    output
+}
+
+/// Cell values known at compile time, by position from the start of a straight-line block
+struct KnownCells {
+    /// Position of the pointer
+    pointer: i64,
+    /// Value of the cells written in the block, or `None` if it is unknown
+    values: HashMap<i64, Option<u64>>,
+    /// Whether the other cells at non-negative positions are 0, at the start of the program
+    zero: bool,
+}
+
+impl KnownCells {
+    /// Cells at the start of the program
+    fn start() -> Self {
+        Self {
+            pointer: 0,
+            values: HashMap::new(),
+            zero: true,
+        }
+    }
+
+    /// Cells in a loop body, where nothing is known
+    fn unknown() -> Self {
+        Self {
+            pointer: 0,
+            values: HashMap::new(),
+            zero: false,
+        }
+    }
+
+    /// Cells after a loop or a scan, where only the current cell is known to be 0
+    fn after_loop() -> Self {
+        Self {
+            pointer: 0,
+            values: HashMap::from([(0, Some(0))]),
+            zero: false,
+        }
+    }
+
+    /// Value of the cell at the given offset from the pointer, if known
+    fn get(&self, offset: i32) -> Option<u64> {
+        let position = self.pointer + offset as i64;
+        match self.values.get(&position) {
+            Some(value) => *value,
+            None if self.zero && position >= 0 => Some(0),
+            None => None,
+        }
+    }
+
+    /// Record the value of the cell at the given offset from the pointer
+    fn set(&mut self, offset: i32, value: Option<u64>) {
+        self.values.insert(self.pointer + offset as i64, value);
+    }
+}
+
+/// Helper: propagates the known cell values through the given nodes, starting with the given known cells
+fn propagate_constants(nodes: &[Node], mut known: KnownCells, width: CellWidth) -> Vec<Node> {
+    let mut output = Vec::new();
+
+    for node in nodes {
+        let (instruction, span) = match node {
+            // The loop never runs
+            Node::Loop { .. } if known.get(0) == Some(0) => continue,
+            Node::Loop { body, open, close } => {
+                // Nothing is known about the cells when entering the body, which may run several times
+                output.push(Node::Loop {
+                    body: propagate_constants(body, KnownCells::unknown(), width),
+                    open: *open,
+                    close: *close,
+                });
+                known = KnownCells::after_loop();
+                continue;
+            }
+            Node::Instruction(instruction, span) => (instruction, *span),
+        };
+
+        // Value added to a cell, if the instruction is an addition
+        let addition = match instruction {
+            ExtendedInstruction::Regular(Instruction::Increment) => Some((0, 1)),
+            ExtendedInstruction::Regular(Instruction::Decrement) => Some((0, width.mask())),
+            ExtendedInstruction::Add { value, offset } => Some((*offset, *value)),
+            ExtendedInstruction::Sub { value, offset } => Some((*offset, value.wrapping_neg())),
+            _ => None,
+        };
+        if let Some((offset, delta)) = addition {
+            match known.get(offset) {
+                Some(value) => {
+                    let value = value.wrapping_add(delta) & width.mask();
+                    push_set_value(&mut output, offset, value, span);
+                    known.set(offset, Some(value));
+                }
+                None => output.push(node.clone()),
+            }
+            continue;
+        }
+
+        match instruction {
+            ExtendedInstruction::Regular(Instruction::MoveRight) => known.pointer += 1,
+            ExtendedInstruction::Regular(Instruction::MoveLeft) => known.pointer -= 1,
+            ExtendedInstruction::JumpRight(n) => known.pointer += *n as i64,
+            ExtendedInstruction::JumpLeft(n) => known.pointer -= *n as i64,
+            ExtendedInstruction::SetZero { offset } => {
+                push_set_value(&mut output, *offset, 0, span);
+                known.set(*offset, Some(0));
+                continue;
+            }
+            ExtendedInstruction::SetValue { value, offset } => {
+                push_set_value(&mut output, *offset, *value, span);
+                known.set(*offset, Some(*value));
+                continue;
+            }
+            ExtendedInstruction::Regular(Instruction::Output)
+            | ExtendedInstruction::Output { .. } => {
+                let offset = match instruction {
+                    ExtendedInstruction::Output { offset } => *offset,
+                    _ => 0,
+                };
+                if let Some(value) = known.get(offset) {
+                    output.push(Node::Instruction(
+                        ExtendedInstruction::OutputValue(value as u8),
+                        span,
+                    ));
+                    continue;
+                }
+            }
+            ExtendedInstruction::Regular(Instruction::Input) => known.set(0, None),
+            ExtendedInstruction::MulAdd { offset, factor } => match known.get(0) {
+                // The target cell is untouched
+                Some(0) => continue,
+                // Folded into an instruction at the target offset, which must fit in a displacement
+                Some(current) if (*offset as i64).abs() <= MAX_OFFSET => {
+                    let product = current.wrapping_mul(*factor) & width.mask();
+                    match known.get(*offset) {
+                        Some(value) => {
+                            let value = value.wrapping_add(product) & width.mask();
+                            push_set_value(&mut output, *offset, value, span);
+                            known.set(*offset, Some(value));
+                        }
+                        None => output.push(Node::Instruction(
+                            ExtendedInstruction::Add {
+                                value: product,
+                                offset: *offset,
+                            },
+                            span,
+                        )),
+                    }
+                    continue;
+                }
+                _ => known.set(*offset, None),
+            },
+            ExtendedInstruction::ScanRight(_) | ExtendedInstruction::ScanLeft(_) => {
+                known = KnownCells::after_loop();
+            }
+            _ => {}
+        }
+
+        output.push(node.clone());
+    }
+
+    output
+}
+
+/// Helper: pushes the instruction setting the cell at the given offset to a value inside the given buffer,
+/// replacing the previous instruction if it already set the same cell
+fn push_set_value(buffer: &mut Vec<Node>, offset: i32, value: u64, span: Span) {
+    let instruction = match value {
+        0 => ExtendedInstruction::SetZero { offset },
+        _ => ExtendedInstruction::SetValue { value, offset },
+    };
+
+    if let Some(Node::Instruction(
+        ExtendedInstruction::SetZero { offset: previous }
+        | ExtendedInstruction::SetValue {
+            offset: previous, ..
+        },
+        previous_span,
+    )) = buffer.last()
+    {
+        if *previous == offset {
+            let span = previous_span.merge(&span);
+            buffer.pop();
+            buffer.push(Node::Instruction(instruction, span));
+            return;
+        }
+    }
+
+    buffer.push(Node::Instruction(instruction, span));
 }
@@ -0,0 +1,222 @@
+//! Configurable pipeline of optimization passes
+//!
+//! The passes always run in the order of [`Pass::ALL`]. Passes working on a flat list of instructions
+//! run first, then the program tree is built and the loop passes run on it.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    config::CellWidth,
+    error::BfError,
+    instructions::Instruction,
+    ir::{build_tree, flatten, Node},
+    lexer::Span,
+    optimizer::{
+        instructions_to_extended, optimize_constants, optimize_dead_loops,
+        optimize_instruction_repetitions, optimize_multiply_loops, optimize_pointer_offsets,
+        optimize_scan_loops,
+    },
+    rewrite::{rewrite, RewriteRule, RULES},
+};
+
+/// An optimization pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    /// Fold runs of `+`, `-`, `>` and `<` into single instructions
+    Repetitions,
+    /// Replace instruction sequences matching rewrite rules, like `[-]`
+    Patterns,
+    /// Replace loops that only move the pointer with scans
+    ScanLoops,
+    /// Replace balanced loops that only add constants to cells with multiplications
+    MultiplyLoops,
+    /// Remove loops that start on a cell known to be 0
+    DeadLoops,
+    /// Fold operations on cells whose value is known at compile time
+    Constants,
+    /// Address cells at an offset from the pointer inside straight-line blocks
+    PointerOffsets,
+}
+
+impl Pass {
+    /// Every pass, in execution order
+    pub const ALL: [Pass; 7] = [
+        Pass::Repetitions,
+        Pass::Patterns,
+        Pass::ScanLoops,
+        Pass::MultiplyLoops,
+        Pass::DeadLoops,
+        Pass::Constants,
+        Pass::PointerOffsets,
+    ];
+
+    /// Name of the pass on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Repetitions => "repetitions",
+            Pass::Patterns => "patterns",
+            Pass::ScanLoops => "scan-loops",
+            Pass::MultiplyLoops => "multiply-loops",
+            Pass::DeadLoops => "dead-loops",
+            Pass::Constants => "constants",
+            Pass::PointerOffsets => "pointer-offsets",
+        }
+    }
+
+    /// Lowest optimization level that enables this pass
+    fn level(&self) -> u8 {
+        match self {
+            Pass::Repetitions | Pass::Patterns | Pass::DeadLoops => 1,
+            Pass::ScanLoops | Pass::MultiplyLoops | Pass::Constants => 2,
+            Pass::PointerOffsets => 3,
+        }
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL.into_iter().find(|pass| pass.name() == s).ok_or(
+            "expected one of: repetitions, patterns, scan-loops, multiply-loops, dead-loops, constants, pointer-offsets",
+        )
+    }
+}
+
+/// What a pass did to the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PassReport {
+    pub pass: Pass,
+    /// Whether the pass modified the program
+    pub changed: bool,
+    /// Number of instructions before the pass, counting loop brackets
+    pub before: usize,
+    /// Number of instructions after the pass, counting loop brackets
+    pub after: usize,
+}
+
+/// Runs the enabled optimization passes on a program
+#[derive(Debug, Clone)]
+pub struct PassManager {
+    /// Enabled passes. They run in the order of `Pass::ALL` regardless of their order here.
+    passes: Vec<Pass>,
+    /// Rewrite rules of the patterns pass, by decreasing priority
+    rules: Vec<RewriteRule>,
+}
+
+impl PassManager {
+    /// Build a pass manager running every pass (`-O3`)
+    pub fn new() -> Self {
+        Self::with_level(3)
+    }
+
+    /// Build a pass manager running the passes of an optimization level:
+    /// - 0: no optimization
+    /// - 1: instruction repetitions, patterns and dead loops
+    /// - 2: scan and multiply loops, constant propagation
+    /// - 3: pointer offsets
+    ///
+    /// Each level includes the passes of the lower ones. Levels above 3 are the same as 3.
+    pub fn with_level(level: u8) -> Self {
+        Self {
+            passes: Pass::ALL
+                .into_iter()
+                .filter(|pass| pass.level() <= level)
+                .collect(),
+            rules: RULES.to_vec(),
+        }
+    }
+
+    /// Enable or disable a single pass
+    pub fn with_pass(mut self, pass: Pass, enabled: bool) -> Self {
+        self.passes.retain(|p| *p != pass);
+        if enabled {
+            self.passes.push(pass);
+        }
+        self
+    }
+
+    /// Register a rewrite rule for the patterns pass, with a lower priority than the rules already registered
+    pub fn with_rule(mut self, rule: RewriteRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Whether the given pass is enabled
+    pub fn is_enabled(&self, pass: Pass) -> bool {
+        self.passes.contains(&pass)
+    }
+
+    /// Optimize a tokenized program into a program tree for cells of the given width.
+    /// Returns a report for each pass that ran, in execution order.
+    pub fn run(
+        &self,
+        program: &[(Instruction, Span)],
+        width: CellWidth,
+    ) -> Result<(Vec<Node>, Vec<PassReport>), BfError> {
+        let mut reports = Vec::new();
+
+        // Passes on the flat list of instructions
+        let mut instructions = instructions_to_extended(program);
+        for pass in [Pass::Repetitions, Pass::Patterns] {
+            if !self.is_enabled(pass) {
+                continue;
+            }
+            let optimized = match pass {
+                Pass::Repetitions => optimize_instruction_repetitions(&instructions, width),
+                _ => rewrite(&instructions, &self.rules),
+            };
+            reports.push(PassReport {
+                pass,
+                changed: optimized != instructions,
+                before: instructions.len(),
+                after: optimized.len(),
+            });
+            instructions = optimized;
+        }
+
+        // Passes on the program tree
+        let mut nodes = build_tree(&instructions)?;
+        let mut count = instructions.len();
+        for pass in [
+            Pass::ScanLoops,
+            Pass::MultiplyLoops,
+            Pass::DeadLoops,
+            Pass::Constants,
+            Pass::PointerOffsets,
+        ] {
+            if !self.is_enabled(pass) {
+                continue;
+            }
+            let optimized = match pass {
+                Pass::ScanLoops => optimize_scan_loops(&nodes),
+                Pass::MultiplyLoops => optimize_multiply_loops(&nodes, width),
+                Pass::DeadLoops => optimize_dead_loops(&nodes),
+                Pass::Constants => optimize_constants(&nodes, width),
+                _ => optimize_pointer_offsets(&nodes),
+            };
+            let optimized_count = flatten(&optimized).len();
+            reports.push(PassReport {
+                pass,
+                changed: optimized != nodes,
+                before: count,
+                after: optimized_count,
+            });
+            (nodes, count) = (optimized, optimized_count);
+        }
+
+        Ok((nodes, reports))
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        PassManager::new()
+    }
+}
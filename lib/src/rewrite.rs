@@ -0,0 +1,136 @@
+//! Rewrite rules for instruction sequences
+//!
+//! A rule matches a sequence of instructions against a pattern, whose elements can capture values,
+//! and replaces it with instructions built from the captured values. Rules are plain data:
+//! library users can declare their own and register them with [`PassManager::with_rule`](crate::passes::PassManager::with_rule).
+//!
+//! Rules run on the flat list of instructions, before loops are built: brackets are instructions like the others.
+
+use crate::{
+    instructions::{ExtendedInstruction, Instruction},
+    lexer::Span,
+};
+
+/// An element of a rewrite pattern, matching a single instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatternElement {
+    /// Exactly this instruction
+    Exact(ExtendedInstruction),
+    /// An addition to the current cell, `+` or `Add`, capturing the value added
+    Add,
+    /// A subtraction from the current cell, `-` or `Sub`, capturing the value subtracted
+    Sub,
+    /// Any instruction but a bracket, capturing nothing
+    Any,
+}
+
+impl PatternElement {
+    /// Whether the element matches the given instruction, pushing the captured value if any
+    fn matches(&self, instruction: &ExtendedInstruction, captures: &mut Vec<u64>) -> bool {
+        let captured = match (self, instruction) {
+            (PatternElement::Exact(expected), instruction) => return expected == instruction,
+            (PatternElement::Add, ExtendedInstruction::Regular(Instruction::Increment)) => 1,
+            (PatternElement::Add, ExtendedInstruction::Add { value, offset: 0 }) => *value,
+            (PatternElement::Sub, ExtendedInstruction::Regular(Instruction::Decrement)) => 1,
+            (PatternElement::Sub, ExtendedInstruction::Sub { value, offset: 0 }) => *value,
+            (PatternElement::Any, instruction) => {
+                return !matches!(
+                    instruction,
+                    ExtendedInstruction::Regular(Instruction::JumpForward)
+                        | ExtendedInstruction::Regular(Instruction::JumpBackwards)
+                );
+            }
+            _ => return false,
+        };
+
+        captures.push(captured);
+        true
+    }
+}
+
+/// A rewrite rule: instructions matching the pattern are replaced if the captured values satisfy the condition.
+///
+/// The replacement must keep the brackets balanced, and do the same thing as the matched instructions.
+#[derive(Debug, Clone, Copy)]
+pub struct RewriteRule {
+    /// Name of the rule, for debugging
+    pub name: &'static str,
+    /// Instructions to match
+    pub pattern: &'static [PatternElement],
+    /// Whether the rule applies, given the values captured by the pattern in order
+    pub condition: fn(&[u64]) -> bool,
+    /// Instructions replacing the match, given the values captured by the pattern in order
+    pub replacement: fn(&[u64]) -> Vec<ExtendedInstruction>,
+}
+
+/// Default rewrite rules, listed in DECREASING PRIORITY ORDER
+pub static RULES: &[RewriteRule] = &[
+    // Set zero pattern: [-], [---], ...
+    // Subtracting an odd value reaches 0 whatever the cell width
+    RewriteRule {
+        name: "clear-sub",
+        pattern: &[
+            PatternElement::Exact(ExtendedInstruction::Regular(Instruction::JumpForward)),
+            PatternElement::Sub,
+            PatternElement::Exact(ExtendedInstruction::Regular(Instruction::JumpBackwards)),
+        ],
+        condition: |captures| captures[0] % 2 == 1,
+        replacement: |_| vec![ExtendedInstruction::SetZero { offset: 0 }],
+    },
+    // Set zero pattern: [+], [+++], ...
+    RewriteRule {
+        name: "clear-add",
+        pattern: &[
+            PatternElement::Exact(ExtendedInstruction::Regular(Instruction::JumpForward)),
+            PatternElement::Add,
+            PatternElement::Exact(ExtendedInstruction::Regular(Instruction::JumpBackwards)),
+        ],
+        condition: |captures| captures[0] % 2 == 1,
+        replacement: |_| vec![ExtendedInstruction::SetZero { offset: 0 }],
+    },
+];
+
+/// Apply rewrite rules to the given instructions, in a single pass from left to right.
+/// At each instruction, the first matching rule is applied and the matched instructions are skipped.
+/// The replacement instructions cover the spans of all the matched instructions.
+pub fn rewrite(
+    instructions: &[(ExtendedInstruction, Span)],
+    rules: &[RewriteRule],
+) -> Vec<(ExtendedInstruction, Span)> {
+    let mut output = Vec::new();
+    let mut captures = Vec::new();
+    let mut index = 0;
+
+    'instructions: while index < instructions.len() {
+        for rule in rules {
+            let Some(candidate) = instructions.get(index..index + rule.pattern.len()) else {
+                continue;
+            };
+
+            captures.clear();
+            let matched = !candidate.is_empty()
+                && rule
+                    .pattern
+                    .iter()
+                    .zip(candidate)
+                    .all(|(element, (instruction, _))| element.matches(instruction, &mut captures));
+
+            if matched && (rule.condition)(&captures) {
+                let span = candidate[0].1.merge(&candidate[candidate.len() - 1].1);
+                output.extend(
+                    (rule.replacement)(&captures)
+                        .into_iter()
+                        .map(|instruction| (instruction, span)),
+                );
+                index += candidate.len();
+                continue 'instructions;
+            }
+        }
+
+        // No rule matches here: keep the instruction
+        output.push(instructions[index]);
+        index += 1;
+    }
+
+    output
+}
@@ -0,0 +1,227 @@
+//! Memory tape of the JIT compiler, optionally surrounded by guard pages
+//!
+//! Guard pages are mapped with `PROT_NONE`, so that any access to them raises a `SIGSEGV`.
+//! While compiled code runs, a signal handler recognizes faults in the guard regions, and resumes
+//! execution at the function epilogue instead of crashing. This gives bounds safety without any
+//! per-instruction cost.
+
+use std::{cell::Cell, ops::Range, sync::OnceLock};
+
+use memmap2::MmapMut;
+
+use crate::error::BfError;
+
+/// Size of each guard region, surrounding the tape on both sides
+pub(crate) const GUARD_SIZE: usize = 1 << 20;
+
+/// Anonymous memory map holding the tape cells, between two optional guard regions.
+///
+/// A growable tape reserves inaccessible memory after its cells: accesses to it are caught by the
+/// signal handler, which makes the memory accessible and resumes execution.
+pub(crate) struct Tape {
+    /// Whole memory map, guard regions included
+    map: MmapMut,
+    /// Size of each guard region (0 if there are none)
+    guard_size: usize,
+    /// Number of accessible bytes
+    size: usize,
+    /// Number of bytes the tape can grow to
+    capacity: usize,
+}
+
+impl Tape {
+    /// Allocate a zeroed tape of `size` bytes, reserving `growth` more bytes after it.
+    /// With guard regions, the tape size is rounded up to a whole number of pages.
+    pub(crate) fn new(size: usize, guard_size: usize, growth: usize) -> Result<Self, BfError> {
+        let size = match guard_size {
+            0 => Some(size),
+            _ => size.checked_next_multiple_of(page_size()),
+        }
+        .ok_or(BfError::TapeTooLarge)?;
+        let capacity = size.checked_add(growth).ok_or(BfError::TapeTooLarge)?;
+        let length = guard_size
+            .checked_mul(2)
+            .and_then(|guards| guards.checked_add(capacity))
+            .ok_or(BfError::TapeTooLarge)?;
+
+        let map = MmapMut::map_anon(length).map_err(|_| BfError::TapeTooLarge)?;
+        let tape = Self {
+            map,
+            guard_size,
+            size,
+            capacity,
+        };
+
+        // Remove all access rights from the guard regions and the reserved cells
+        let [left, right] = tape.guards();
+        for region in [left, tape.reserve(), right] {
+            if !region.is_empty() {
+                protect(region, libc::PROT_NONE);
+            }
+        }
+
+        Ok(tape)
+    }
+
+    /// Addresses of the first cell and past the last accessible cell
+    pub(crate) fn as_ptr_range(&self) -> Range<*const u8> {
+        let start = unsafe { self.map.as_ptr().add(self.guard_size) };
+        start..unsafe { start.add(self.size) }
+    }
+
+    /// Accessible cells, as raw bytes
+    pub(crate) fn cells(&self) -> &[u8] {
+        &self.map[self.guard_size..self.guard_size + self.size]
+    }
+
+    /// Addresses of the first cell and past the last cell the tape can grow to
+    pub(crate) fn bounds(&self) -> Range<*const u8> {
+        let start = self.as_ptr_range().start;
+        start..unsafe { start.add(self.capacity) }
+    }
+
+    /// Address ranges of the guard regions, before and after the tape
+    pub(crate) fn guards(&self) -> [Range<usize>; 2] {
+        let bounds = self.bounds();
+        let (start, end) = (bounds.start as usize, bounds.end as usize);
+        [start - self.guard_size..start, end..end + self.guard_size]
+    }
+
+    /// Address range of the reserved cells, that are not accessible yet
+    pub(crate) fn reserve(&self) -> Range<usize> {
+        self.as_ptr_range().end as usize..self.bounds().end as usize
+    }
+
+    /// Update the number of accessible cells, after the tape grew up to the given address
+    pub(crate) fn grow_to(&mut self, end: usize) {
+        self.size = end - self.as_ptr_range().start as usize;
+    }
+
+    /// Reset all cells to 0
+    pub(crate) fn clear(&mut self) {
+        let start = self.guard_size;
+        self.map[start..start + self.size].fill(0);
+    }
+}
+
+// ********************************************************************************************* //
+//                                        SIGNAL HANDLING                                        //
+// ********************************************************************************************* //
+
+/// Description of the compiled code running on the current thread, used by the signal handler
+pub(crate) struct GuardState {
+    /// Address ranges that must not be accessed
+    pub(crate) guards: [Range<usize>; 2],
+    /// Reserved addresses that are made accessible when the tape grows
+    pub(crate) reserve: Cell<Range<usize>>,
+    /// Address range of the compiled code
+    pub(crate) code: Range<usize>,
+    /// Address at which to resume after a fault in a guard region
+    pub(crate) exit: usize,
+    /// Offset in the compiled code of the faulting instruction, if any
+    pub(crate) fault: Cell<Option<usize>>,
+    /// Tape pointer when the faulting instruction ran
+    pub(crate) pointer: Cell<usize>,
+}
+
+thread_local! {
+    /// Compiled code currently running on this thread, if it is protected by guard pages
+    static ACTIVE: Cell<*const GuardState> = const { Cell::new(std::ptr::null()) };
+}
+
+/// SIGSEGV handler installed before ours, called for faults that do not come from compiled code
+static PREVIOUS_HANDLER: OnceLock<libc::sigaction> = OnceLock::new();
+
+/// Run `f` with the signal handler watching the guard regions described by `state`
+pub(crate) fn with_guard<T>(state: &GuardState, f: impl FnOnce() -> T) -> T {
+    PREVIOUS_HANDLER.get_or_init(install_handler);
+
+    let previous = ACTIVE.with(|active| active.replace(state));
+    let result = f();
+    ACTIVE.with(|active| active.set(previous));
+
+    result
+}
+
+/// Install the SIGSEGV handler for the whole process, returning the previous one
+fn install_handler() -> libc::sigaction {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_segv as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        let result = libc::sigaction(libc::SIGSEGV, &action, &mut previous);
+        assert_eq!(result, 0, "Failed to install the SIGSEGV handler");
+        previous
+    }
+}
+
+/// SIGSEGV handler: grow the tape on faults in the reserved region,
+/// and redirect faults from compiled code in a guard region to the exit address
+extern "C" fn handle_segv(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    raw_context: *mut libc::c_void,
+) {
+    unsafe {
+        let context = &mut *(raw_context as *mut libc::ucontext_t);
+        let rip = &mut context.uc_mcontext.gregs[libc::REG_RIP as usize];
+        let address = (*info).si_addr() as usize;
+
+        if let Some(state) = ACTIVE.with(|active| active.get()).as_ref() {
+            // Make the reserved memory accessible up to the faulting page, and retry the instruction
+            let reserve = state.reserve.take();
+            if reserve.contains(&address) {
+                let end = (address + 1).next_multiple_of(page_size());
+                protect(reserve.start..end, libc::PROT_READ | libc::PROT_WRITE);
+                state.reserve.set(end..reserve.end);
+                return;
+            }
+            state.reserve.set(reserve);
+
+            let in_guard = state.guards.iter().any(|guard| guard.contains(&address));
+
+            if in_guard && state.code.contains(&(*rip as usize)) {
+                state.fault.set(Some(*rip as usize - state.code.start));
+                *rip = state.exit as libc::greg_t;
+                let pointer = context.uc_mcontext.gregs[libc::REG_R13 as usize];
+                state.pointer.set(pointer as usize);
+                return;
+            }
+        }
+
+        // Not our fault: hand it to the previous handler, keeping ours for the next faults
+        match PREVIOUS_HANDLER.get() {
+            Some(previous) if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                    std::mem::transmute(previous.sa_sigaction);
+                handler(signal, info, raw_context);
+            }
+            Some(previous)
+                if previous.sa_sigaction != libc::SIG_DFL
+                    && previous.sa_sigaction != libc::SIG_IGN =>
+            {
+                let handler: extern "C" fn(libc::c_int) =
+                    std::mem::transmute(previous.sa_sigaction);
+                handler(signal);
+            }
+            // The default action terminates the process when the instruction faults again
+            _ => {
+                libc::signal(libc::SIGSEGV, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
+/// Change the access rights of a page-aligned address range
+fn protect(range: Range<usize>, protection: libc::c_int) {
+    let result = unsafe { libc::mprotect(range.start as *mut _, range.len(), protection) };
+    assert_eq!(result, 0, "Failed to change the tape memory protection");
+}
+
+/// Size of a memory page
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
@@ -0,0 +1,438 @@
+//! Transpilers from brainfuck to other languages
+//!
+//! They lower the optimized program tree into source code, one line per instruction, with loops as `while` loops.
+//! Their tape can neither grow nor wrap around. C programs read from stdin and write to stdout, and like
+//! standalone executables, they do not check the tape bounds. Rust modules use the streams passed to `run`,
+//! and panic when the pointer leaves the tape.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    config::{CellWidth, EofBehavior},
+    error::BfError,
+    instructions::{ExtendedInstruction, Instruction},
+    ir::Node,
+    lexer::Span,
+    passes::{PassManager, PassReport},
+    wasm,
+};
+
+/// A language to transpile to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// A C program, with the tape in a static array
+    C,
+    /// A Rust module defining `run(input, output)`, with the tape in a vector
+    Rust,
+    /// A WebAssembly module in the text format, see [`wasm`]
+    Wat,
+}
+
+impl Target {
+    /// Name of the target on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Target::C => "c",
+            Target::Rust => "rust",
+            Target::Wat => "wat",
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Target {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "c" => Ok(Target::C),
+            "rust" | "rs" => Ok(Target::Rust),
+            "wat" => Ok(Target::Wat),
+            _ => Err("expected one of: c, rust, wat"),
+        }
+    }
+}
+
+/// Input function of the Rust modules, which reads a byte like the interpreter does
+const RUST_READ_BYTE: &str = r#"
+/// Read a byte from `input`, flushing the pending output first. Returns `None` at end of input.
+fn read_byte(input: &mut impl Read, output: &mut impl Write) -> io::Result<Option<u8>> {
+    output.flush()?;
+    let mut byte = [0];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+}
+"#;
+
+/// A transpiler from brainfuck to other languages
+#[derive(Debug, Clone)]
+pub struct Transpiler {
+    /// Number of cells on the tape
+    tape_size: usize,
+    /// Size of the memory cells
+    cell_width: CellWidth,
+    /// What `,` stores in the current cell at end of input
+    eof_behavior: EofBehavior,
+
+    /// Optimization passes run before transpiling
+    passes: PassManager,
+    /// What each optimization pass did during the last transpilation
+    pass_reports: Vec<PassReport>,
+}
+
+impl Transpiler {
+    /// Build a transpiler with a tape of 30 000 8-bit cells, running every optimization pass
+    pub fn new() -> Self {
+        Self {
+            tape_size: 30_000,
+            cell_width: CellWidth::default(),
+            eof_behavior: EofBehavior::default(),
+            passes: PassManager::new(),
+            pass_reports: Vec::new(),
+        }
+    }
+
+    /// Set the number of cells on the tape
+    pub fn with_tape_size(mut self, tape_size: usize) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    /// Set the size of the memory cells
+    pub fn with_cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
+        self
+    }
+
+    /// Set what `,` stores in the current cell at end of input
+    pub fn with_eof_behavior(mut self, eof_behavior: EofBehavior) -> Self {
+        self.eof_behavior = eof_behavior;
+        self
+    }
+
+    /// Set the optimization passes run before transpiling
+    pub fn with_passes(mut self, passes: PassManager) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// What each optimization pass did during the last transpilation, in execution order
+    pub fn pass_reports(&self) -> &[PassReport] {
+        &self.pass_reports
+    }
+
+    /// Transpile the brainfuck source code into a complete program in the target language
+    pub fn transpile(
+        &mut self,
+        source: &[(Instruction, Span)],
+        target: Target,
+    ) -> Result<String, BfError> {
+        let (nodes, reports) = self.passes.run(source, self.cell_width)?;
+        self.pass_reports = reports;
+
+        let mut code = String::new();
+        match target {
+            Target::C => self.c_program(&nodes, &mut code),
+            Target::Rust => self.rust_program(&nodes, &mut code),
+            Target::Wat => code = self.wasm_module(&nodes)?.text(),
+        }
+        Ok(code)
+    }
+
+    /// Compile the brainfuck source code into a WebAssembly module, returned in the binary format
+    pub fn compile_wasm(&mut self, source: &[(Instruction, Span)]) -> Result<Vec<u8>, BfError> {
+        let (nodes, reports) = self.passes.run(source, self.cell_width)?;
+        self.pass_reports = reports;
+        Ok(self.wasm_module(&nodes)?.binary())
+    }
+
+    /// WebAssembly module running the program tree
+    fn wasm_module(&self, nodes: &[Node]) -> Result<wasm::Module, BfError> {
+        wasm::Module::new(nodes, self.tape_size, self.cell_width, self.eof_behavior)
+    }
+
+    /// Write a C program running the program tree
+    fn c_program(&self, nodes: &[Node], code: &mut String) {
+        let cell = c_cell_type(self.cell_width);
+
+        code.push_str("/* Transpiled from brainfuck */\n");
+        code.push_str("#include <stdint.h>\n#include <stdio.h>\n\n");
+        code.push_str(&format!("static {cell} tape[{}];\n\n", self.tape_size));
+
+        // Only define the input function if it is used, to avoid warnings
+        if uses_input(nodes) {
+            code.push_str("/* Read a byte from stdin, flushing pending output first */\n");
+            match self.eof_behavior.value(self.cell_width) {
+                None => {
+                    code.push_str(&format!("static {cell} input({cell} cell) {{\n"));
+                    code.push_str("    fflush(stdout);\n    int c = getchar();\n");
+                    code.push_str(&format!("    return c == EOF ? cell : ({cell})c;\n}}\n\n"));
+                }
+                Some(eof) => {
+                    code.push_str(&format!("static {cell} input(void) {{\n"));
+                    code.push_str("    fflush(stdout);\n    int c = getchar();\n");
+                    // Both branches have the cell type, to avoid mixing signed and unsigned operands
+                    let eof = match eof == self.cell_width.mask() {
+                        true => format!("({cell})-1"),
+                        false => format!("({cell}){}", c_literal(eof)),
+                    };
+                    code.push_str(&format!("    return c == EOF ? {eof} : ({cell})c;\n}}\n\n"));
+                }
+            }
+        }
+
+        code.push_str("int main(void) {\n");
+        code.push_str(&format!("    {cell} *p = tape;\n"));
+        self.write_nodes(nodes, Self::c_statement, "while (*p)", 1, code);
+        code.push_str("    return 0;\n}\n");
+    }
+
+    /// Write a Rust module whose `run` function runs the program tree
+    fn rust_program(&self, nodes: &[Node], code: &mut String) {
+        let cell = rust_cell_type(self.cell_width);
+
+        code.push_str("//! Transpiled from brainfuck\n\n");
+        code.push_str("use std::io::{self, Read, Write};\n\n");
+
+        code.push_str(
+            "/// Run the program, reading `,` from `input` and writing `.` to `output`\n",
+        );
+        code.push_str("#[allow(unused_assignments, unused_mut, unused_variables)]\n");
+        code.push_str(
+            "pub fn run(input: &mut impl Read, output: &mut impl Write) -> io::Result<()> {\n",
+        );
+        code.push_str(&format!(
+            "    let mut tape = vec![0{cell}; {}];\n",
+            self.tape_size
+        ));
+        code.push_str("    let mut p: usize = 0;\n");
+        self.write_nodes(nodes, Self::rust_statement, "while tape[p] != 0", 1, code);
+        code.push_str("    output.flush()\n}\n");
+
+        // Only define the input function if it is used, to avoid warnings
+        if uses_input(nodes) {
+            code.push_str(RUST_READ_BYTE);
+        }
+    }
+
+    /// Write the statements of the nodes, indented by the given level.
+    /// Loops are blocks starting with the given condition, that the current cell is not 0.
+    fn write_nodes(
+        &self,
+        nodes: &[Node],
+        statement: fn(&Self, &ExtendedInstruction) -> String,
+        condition: &str,
+        level: usize,
+        code: &mut String,
+    ) {
+        let indent = "    ".repeat(level);
+        for node in nodes {
+            match node {
+                Node::Instruction(instruction, _) => {
+                    code.push_str(&indent);
+                    code.push_str(&statement(self, instruction));
+                    code.push('\n');
+                }
+                Node::Loop { body, .. } => {
+                    code.push_str(&format!("{indent}{condition} {{\n"));
+                    self.write_nodes(body, statement, condition, level + 1, code);
+                    code.push_str(&format!("{indent}}}\n"));
+                }
+            }
+        }
+    }
+
+    /// C statement of a single instruction, which is never a bracket
+    fn c_statement(&self, instruction: &ExtendedInstruction) -> String {
+        let mask = self.cell_width.mask();
+        match *instruction {
+            ExtendedInstruction::Regular(Instruction::MoveRight) => "p += 1;".to_string(),
+            ExtendedInstruction::Regular(Instruction::MoveLeft) => "p -= 1;".to_string(),
+            ExtendedInstruction::Regular(Instruction::Increment) => "*p += 1;".to_string(),
+            ExtendedInstruction::Regular(Instruction::Decrement) => "*p -= 1;".to_string(),
+            ExtendedInstruction::Regular(Instruction::Output) => self.c_output(0),
+            ExtendedInstruction::Regular(Instruction::Input) => match self.eof_behavior {
+                EofBehavior::Unchanged => "*p = input(*p);".to_string(),
+                _ => "*p = input();".to_string(),
+            },
+            ExtendedInstruction::Regular(Instruction::JumpForward)
+            | ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
+                unreachable!("brackets are loop nodes")
+            }
+            ExtendedInstruction::Add { value, offset } => {
+                format!("{} += {};", c_cell(offset), c_literal(value & mask))
+            }
+            ExtendedInstruction::Sub { value, offset } => {
+                format!("{} -= {};", c_cell(offset), c_literal(value & mask))
+            }
+            ExtendedInstruction::JumpRight(n) => format!("p += {n};"),
+            ExtendedInstruction::JumpLeft(n) => format!("p -= {n};"),
+            ExtendedInstruction::SetZero { offset } => format!("{} = 0;", c_cell(offset)),
+            ExtendedInstruction::SetValue { value, offset } => {
+                format!("{} = {};", c_cell(offset), c_literal(value & mask))
+            }
+            ExtendedInstruction::Output { offset } => self.c_output(offset),
+            ExtendedInstruction::OutputValue(value) => format!("putchar({value});"),
+            // The unsigned factor avoids signed overflows when small cells are promoted to int.
+            ExtendedInstruction::MulAdd { offset, factor } => match factor & mask {
+                1 => format!("if (*p) {} += *p;", c_cell(offset)),
+                factor => format!("if (*p) {} += *p * {factor}u;", c_cell(offset)),
+            },
+            ExtendedInstruction::ScanRight(stride) => format!("while (*p) p += {stride};"),
+            ExtendedInstruction::ScanLeft(stride) => format!("while (*p) p -= {stride};"),
+        }
+    }
+
+    /// C statement printing the low byte of the cell at an offset from the pointer
+    fn c_output(&self, offset: i32) -> String {
+        match self.cell_width {
+            CellWidth::U8 => format!("putchar({});", c_cell(offset)),
+            _ => format!("putchar((unsigned char){});", c_cell(offset)),
+        }
+    }
+
+    /// Rust statement of a single instruction, which is never a bracket.
+    /// Cell arithmetic wraps around explicitly, and tape accesses are bounds checked.
+    fn rust_statement(&self, instruction: &ExtendedInstruction) -> String {
+        let mask = self.cell_width.mask();
+        match *instruction {
+            ExtendedInstruction::Regular(Instruction::MoveRight) => "p += 1;".to_string(),
+            ExtendedInstruction::Regular(Instruction::MoveLeft) => "p -= 1;".to_string(),
+            ExtendedInstruction::Regular(Instruction::Increment) => {
+                "tape[p] = tape[p].wrapping_add(1);".to_string()
+            }
+            ExtendedInstruction::Regular(Instruction::Decrement) => {
+                "tape[p] = tape[p].wrapping_sub(1);".to_string()
+            }
+            ExtendedInstruction::Regular(Instruction::Output) => self.rust_output(0),
+            ExtendedInstruction::Regular(Instruction::Input) => {
+                match self.eof_behavior.value(self.cell_width) {
+                    None => {
+                        "if let Some(byte) = read_byte(input, output)? { tape[p] = byte.into(); }"
+                            .to_string()
+                    }
+                    Some(eof) => {
+                        format!("tape[p] = read_byte(input, output)?.map_or({eof}, Into::into);")
+                    }
+                }
+            }
+            ExtendedInstruction::Regular(Instruction::JumpForward)
+            | ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
+                unreachable!("brackets are loop nodes")
+            }
+            ExtendedInstruction::Add { value, offset } => {
+                let cell = rust_cell(offset);
+                format!("{cell} = {cell}.wrapping_add({});", value & mask)
+            }
+            ExtendedInstruction::Sub { value, offset } => {
+                let cell = rust_cell(offset);
+                format!("{cell} = {cell}.wrapping_sub({});", value & mask)
+            }
+            ExtendedInstruction::JumpRight(n) => format!("p += {n};"),
+            ExtendedInstruction::JumpLeft(n) => format!("p -= {n};"),
+            ExtendedInstruction::SetZero { offset } => format!("{} = 0;", rust_cell(offset)),
+            ExtendedInstruction::SetValue { value, offset } => {
+                format!("{} = {};", rust_cell(offset), value & mask)
+            }
+            ExtendedInstruction::Output { offset } => self.rust_output(offset),
+            ExtendedInstruction::OutputValue(value) => format!("output.write_all(&[{value}])?;"),
+            ExtendedInstruction::MulAdd { offset, factor } => {
+                let cell = rust_cell(offset);
+                format!(
+                    "if tape[p] != 0 {{ {cell} = {cell}.wrapping_add(tape[p].wrapping_mul({})); }}",
+                    factor & mask
+                )
+            }
+            ExtendedInstruction::ScanRight(stride) => {
+                format!("while tape[p] != 0 {{ p += {stride}; }}")
+            }
+            ExtendedInstruction::ScanLeft(stride) => {
+                format!("while tape[p] != 0 {{ p -= {stride}; }}")
+            }
+        }
+    }
+
+    /// Rust statement writing the low byte of the cell at an offset from the pointer
+    fn rust_output(&self, offset: i32) -> String {
+        match self.cell_width {
+            CellWidth::U8 => format!("output.write_all(&[{}])?;", rust_cell(offset)),
+            _ => format!("output.write_all(&[{} as u8])?;", rust_cell(offset)),
+        }
+    }
+}
+
+impl Default for Transpiler {
+    fn default() -> Self {
+        Transpiler::new()
+    }
+}
+
+// ********************************************************************************************* //
+//                                           HELPER FUNCTIONS                                    //
+// ********************************************************************************************* //
+
+/// Helper: whether the program reads input
+fn uses_input(nodes: &[Node]) -> bool {
+    nodes.iter().any(|node| match node {
+        Node::Instruction(instruction, _) => {
+            *instruction == ExtendedInstruction::Regular(Instruction::Input)
+        }
+        Node::Loop { body, .. } => uses_input(body),
+    })
+}
+
+/// Helper: C type of the cells of the given width
+fn c_cell_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "unsigned char",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+        CellWidth::U64 => "uint64_t",
+    }
+}
+
+/// Helper: C expression of the cell at an offset from the pointer
+fn c_cell(offset: i32) -> String {
+    match offset {
+        0 => "*p".to_string(),
+        _ => format!("p[{offset}]"),
+    }
+}
+
+/// Helper: C literal of a cell value. Values that do not fit in an int are unsigned.
+fn c_literal(value: u64) -> String {
+    match value > i32::MAX as u64 {
+        true => format!("{value}u"),
+        false => value.to_string(),
+    }
+}
+
+/// Helper: Rust type of the cells of the given width
+fn rust_cell_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8 => "u8",
+        CellWidth::U16 => "u16",
+        CellWidth::U32 => "u32",
+        CellWidth::U64 => "u64",
+    }
+}
+
+/// Helper: Rust expression of the cell at an offset from the pointer
+fn rust_cell(offset: i32) -> String {
+    match offset {
+        0 => "tape[p]".to_string(),
+        1.. => format!("tape[p + {offset}]"),
+        _ => format!("tape[p - {}]", offset.unsigned_abs()),
+    }
+}
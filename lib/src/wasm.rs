@@ -0,0 +1,610 @@
+//! WebAssembly module writer, in text (`.wat`) and binary (`.wasm`) formats
+//!
+//! The program tree is lowered once into a list of WebAssembly instructions, which are then either printed
+//! or encoded: both formats describe the same module.
+//!
+//! Modules import two functions from the host, which work like their C counterparts:
+//! - `env.putchar(c: i32)` writes the low byte of `c`
+//! - `env.getchar() -> i32` reads a byte, or returns -1 at end of input
+//!
+//! They export their linear memory as `memory`, and the program as a function `run` without arguments.
+//! The tape starts at address 0, and the pointer is the local `$p`, holding the address of the current cell.
+//!
+//! The memory is a whole number of pages, so it may extend past the tape. Pointer moves and accesses at an
+//! offset are checked against the tape size, and trap with `unreachable` if they would leave the tape:
+//! ```wat
+//! local.get $p
+//! i32.const 29999   ;; tape size minus the move, in bytes
+//! i32.ge_u
+//! if
+//!   unreachable
+//! end
+//! ```
+//! This way programs can run untrusted: the host only exposes I/O.
+//!
+//! Cells are `i32` values for widths up to 32 bits, loaded and stored with the width of the cell, and `i64` values
+//! for 64-bit cells. Loops are a `loop` inside a `block`, leaving the block when the current cell is 0:
+//! ```wat
+//! block
+//!   loop
+//!     local.get $p
+//!     i32.load8_u
+//!     i32.eqz
+//!     br_if 1
+//!     ;; body
+//!     br 0
+//!   end
+//! end
+//! ```
+//!
+//! Input stores the byte read, or the value for end of input, with a `select`:
+//! ```wat
+//! local.get $p
+//! call $getchar
+//! local.tee $c
+//! i32.const 0       ;; or the current cell, to leave it unchanged
+//! local.get $c
+//! i32.const 0
+//! i32.ge_s
+//! select
+//! i32.store8
+//! ```
+
+use crate::{
+    config::{CellWidth, EofBehavior, TapeConfig},
+    error::BfError,
+    instructions::{ExtendedInstruction, Instruction},
+    ir::Node,
+};
+
+/// Size of a page of linear memory
+const PAGE_SIZE: usize = 0x10000;
+
+/// Imported functions, by index
+const PUTCHAR: u32 = 0;
+const GETCHAR: u32 = 1;
+const FUNCTION_NAMES: [&str; 2] = ["$putchar", "$getchar"];
+
+/// Locals of the `run` function, by index: the tape pointer and the last byte read
+const POINTER: u32 = 0;
+const INPUT: u32 = 1;
+const LOCAL_NAMES: [&str; 2] = ["$p", "$c"];
+
+/// A WebAssembly instruction. Cell operations use the value type of the cell width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Block,
+    Loop,
+    If,
+    End,
+    Unreachable,
+    Br(u32),
+    BrIf(u32),
+    Call(u32),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    I32Const(i32),
+    I32Add,
+    I32Sub,
+    I32GeS,
+    I32GeU,
+    I32LtU,
+    I32WrapI64,
+    I64ExtendI32U,
+    /// Load a cell from the address on the stack plus an offset
+    Load(CellWidth, u32),
+    /// Store a cell to the address on the stack plus an offset
+    Store(CellWidth, u32),
+    Const(CellWidth, u64),
+    Add(CellWidth),
+    Sub(CellWidth),
+    Mul(CellWidth),
+    Eqz(CellWidth),
+}
+
+/// A WebAssembly module running a brainfuck program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Module {
+    /// Instructions of the `run` function
+    body: Vec<Op>,
+    /// Size of the linear memory, in pages
+    pages: u32,
+}
+
+impl Module {
+    /// Lower a program tree into a module with a tape of `tape_size` cells of the given width.
+    /// Fails if the tape size in bytes does not fit in a 32-bit address.
+    pub fn new(
+        nodes: &[Node],
+        tape_size: usize,
+        width: CellWidth,
+        eof_behavior: EofBehavior,
+    ) -> Result<Self, BfError> {
+        let tape = TapeConfig {
+            size: tape_size,
+            ..TapeConfig::default()
+        };
+        let tape_bytes = u32::try_from(tape.bytes(width)?).map_err(|_| BfError::TapeTooLarge)?;
+        let pages = (tape_bytes as usize).div_ceil(PAGE_SIZE).max(1);
+
+        let mut body = Vec::new();
+        lower_nodes(nodes, width, eof_behavior, tape_bytes, &mut body);
+        Ok(Self {
+            body,
+            pages: pages as u32,
+        })
+    }
+
+    /// Module in the WebAssembly text format
+    pub fn text(&self) -> String {
+        let mut text = String::from("(module\n");
+        text.push_str("  (import \"env\" \"putchar\" (func $putchar (param i32)))\n");
+        text.push_str("  (import \"env\" \"getchar\" (func $getchar (result i32)))\n");
+        text.push_str(&format!("  (memory (export \"memory\") {})\n", self.pages));
+        text.push_str("  (func (export \"run\") (local $p i32) (local $c i32)\n");
+
+        let mut level = 2;
+        for op in &self.body {
+            if *op == Op::End {
+                level -= 1;
+            }
+            text.push_str(&"  ".repeat(level));
+            text.push_str(&op_text(op));
+            text.push('\n');
+            if matches!(op, Op::Block | Op::Loop | Op::If) {
+                level += 1;
+            }
+        }
+
+        text.push_str("  )\n)\n");
+        text
+    }
+
+    /// Module in the WebAssembly binary format
+    pub fn binary(&self) -> Vec<u8> {
+        let mut bytes = b"\0asm".to_vec();
+        bytes.extend(1u32.to_le_bytes()); // version
+
+        // Types: putchar, getchar and run
+        let mut types = unsigned(3);
+        types.extend([0x60, 1, 0x7f, 0]); // (i32) -> ()
+        types.extend([0x60, 0, 1, 0x7f]); // () -> i32
+        types.extend([0x60, 0, 0]); // () -> ()
+        bytes.extend(section(1, types));
+
+        let mut imports = unsigned(2);
+        imports.extend(name("env"));
+        imports.extend(name("putchar"));
+        imports.extend([0x00, 0]); // function of type 0
+        imports.extend(name("env"));
+        imports.extend(name("getchar"));
+        imports.extend([0x00, 1]); // function of type 1
+        bytes.extend(section(2, imports));
+
+        // Functions: run, of type 2
+        bytes.extend(section(3, vec![1, 2]));
+
+        // Memory: minimum size, without maximum
+        let mut memories = vec![1, 0x00];
+        memories.extend(unsigned(self.pages as u64));
+        bytes.extend(section(5, memories));
+
+        let mut exports = unsigned(2);
+        exports.extend(name("memory"));
+        exports.extend([0x02, 0]); // memory 0
+        exports.extend(name("run"));
+        exports.extend([0x00, 2]); // function 2, after the imports
+        bytes.extend(section(7, exports));
+
+        // Code: the body of run, with a single group of 2 i32 locals
+        let mut code = vec![1, 2, 0x7f];
+        for op in &self.body {
+            code.extend(op_binary(op));
+        }
+        code.push(0x0b); // end
+        let mut bodies = unsigned(1);
+        bodies.extend(unsigned(code.len() as u64));
+        bodies.extend(code);
+        bytes.extend(section(10, bodies));
+
+        bytes
+    }
+}
+
+// ********************************************************************************************* //
+//                                           HELPER FUNCTIONS                                    //
+// ********************************************************************************************* //
+
+/// Helper: lower nodes into WebAssembly instructions, on a tape of `tape_bytes` bytes
+fn lower_nodes(
+    nodes: &[Node],
+    width: CellWidth,
+    eof_behavior: EofBehavior,
+    tape_bytes: u32,
+    ops: &mut Vec<Op>,
+) {
+    for node in nodes {
+        match node {
+            Node::Instruction(instruction, _) => {
+                lower_instruction(instruction, width, eof_behavior, tape_bytes, ops)
+            }
+            Node::Loop { body, .. } => {
+                ops.extend([Op::Block, Op::Loop]);
+                ops.extend(exit_if_zero(width, 1));
+                lower_nodes(body, width, eof_behavior, tape_bytes, ops);
+                ops.extend([Op::Br(0), Op::End, Op::End]);
+            }
+        }
+    }
+}
+
+/// Helper: lower a single instruction, which is never a bracket
+fn lower_instruction(
+    instruction: &ExtendedInstruction,
+    width: CellWidth,
+    eof_behavior: EofBehavior,
+    tape_bytes: u32,
+    ops: &mut Vec<Op>,
+) {
+    let mask = width.mask();
+    // Cells at an offset from the pointer are checked before they are accessed
+    let check = |offset: i64, ops: &mut Vec<Op>| {
+        check_bounds(offset * width.bytes() as i64, tape_bytes, ops)
+    };
+    match *instruction {
+        ExtendedInstruction::Regular(Instruction::MoveRight) => {
+            check(1, ops);
+            ops.extend(move_pointer(1, Op::I32Add, width))
+        }
+        ExtendedInstruction::Regular(Instruction::MoveLeft) => {
+            check(-1, ops);
+            ops.extend(move_pointer(1, Op::I32Sub, width))
+        }
+        ExtendedInstruction::Regular(Instruction::Increment) => {
+            update_cell(0, 1, Op::Add(width), width, ops)
+        }
+        ExtendedInstruction::Regular(Instruction::Decrement) => {
+            update_cell(0, 1, Op::Sub(width), width, ops)
+        }
+        ExtendedInstruction::Regular(Instruction::Output) => output(0, width, ops),
+        ExtendedInstruction::Regular(Instruction::Input) => {
+            ops.extend([
+                Op::LocalGet(POINTER),
+                Op::Call(GETCHAR),
+                Op::LocalTee(INPUT),
+            ]);
+            if width == CellWidth::U64 {
+                ops.push(Op::I64ExtendI32U);
+            }
+            match eof_behavior.value(width) {
+                Some(value) => ops.push(Op::Const(width, value)),
+                None => ops.extend([Op::LocalGet(POINTER), Op::Load(width, 0)]),
+            }
+            ops.extend([Op::LocalGet(INPUT), Op::I32Const(0), Op::I32GeS, Op::Select]);
+            ops.push(Op::Store(width, 0));
+        }
+        ExtendedInstruction::Regular(Instruction::JumpForward)
+        | ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
+            unreachable!("brackets are loop nodes")
+        }
+        ExtendedInstruction::Add { value, offset } => {
+            check(offset as i64, ops);
+            update_cell(offset, value & mask, Op::Add(width), width, ops)
+        }
+        ExtendedInstruction::Sub { value, offset } => {
+            check(offset as i64, ops);
+            update_cell(offset, value & mask, Op::Sub(width), width, ops)
+        }
+        ExtendedInstruction::JumpRight(n) => {
+            check(n as i64, ops);
+            ops.extend(move_pointer(n, Op::I32Add, width))
+        }
+        ExtendedInstruction::JumpLeft(n) => {
+            check(-(n as i64), ops);
+            ops.extend(move_pointer(n, Op::I32Sub, width))
+        }
+        ExtendedInstruction::SetZero { offset } => {
+            check(offset as i64, ops);
+            let memory_offset = cell_address(offset, width, ops);
+            ops.extend([Op::Const(width, 0), Op::Store(width, memory_offset)]);
+        }
+        ExtendedInstruction::SetValue { value, offset } => {
+            check(offset as i64, ops);
+            let memory_offset = cell_address(offset, width, ops);
+            ops.extend([
+                Op::Const(width, value & mask),
+                Op::Store(width, memory_offset),
+            ]);
+        }
+        ExtendedInstruction::Output { offset } => {
+            check(offset as i64, ops);
+            output(offset, width, ops)
+        }
+        ExtendedInstruction::OutputValue(value) => {
+            ops.extend([Op::I32Const(value as i32), Op::Call(PUTCHAR)])
+        }
+        ExtendedInstruction::MulAdd { offset, factor } => {
+            // Skipped when the current cell is 0
+            ops.push(Op::Block);
+            ops.extend(exit_if_zero(width, 0));
+            check(offset as i64, ops);
+            let memory_offset = cell_address(offset, width, ops);
+            cell_address(offset, width, ops);
+            ops.push(Op::Load(width, memory_offset));
+            ops.extend([Op::LocalGet(POINTER), Op::Load(width, 0)]);
+            ops.extend([
+                Op::Const(width, factor & mask),
+                Op::Mul(width),
+                Op::Add(width),
+            ]);
+            ops.extend([Op::Store(width, memory_offset), Op::End]);
+        }
+        ExtendedInstruction::ScanRight(stride) => {
+            ops.extend([Op::Block, Op::Loop]);
+            ops.extend(exit_if_zero(width, 1));
+            check(stride as i64, ops);
+            ops.extend(move_pointer(stride, Op::I32Add, width));
+            ops.extend([Op::Br(0), Op::End, Op::End]);
+        }
+        ExtendedInstruction::ScanLeft(stride) => {
+            ops.extend([Op::Block, Op::Loop]);
+            ops.extend(exit_if_zero(width, 1));
+            check(-(stride as i64), ops);
+            ops.extend(move_pointer(stride, Op::I32Sub, width));
+            ops.extend([Op::Br(0), Op::End, Op::End]);
+        }
+    }
+}
+
+/// Helper: move the pointer by `n` cells to the right, or to the left with `Op::I32Sub`
+fn move_pointer(n: u32, op: Op, width: CellWidth) -> [Op; 4] {
+    [
+        Op::LocalGet(POINTER),
+        Op::I32Const(n.wrapping_mul(width.bytes() as u32) as i32),
+        op,
+        Op::LocalSet(POINTER),
+    ]
+}
+
+/// Helper: branch to the enclosing block at the given depth if the current cell is 0
+fn exit_if_zero(width: CellWidth, depth: u32) -> [Op; 4] {
+    [
+        Op::LocalGet(POINTER),
+        Op::Load(width, 0),
+        Op::Eqz(width),
+        Op::BrIf(depth),
+    ]
+}
+
+/// Helper: push the address of the cell at an offset from the pointer, and return the offset to load it with.
+/// Memory offsets are unsigned: cells on the left are addressed by subtracting from the pointer.
+fn cell_address(offset: i32, width: CellWidth, ops: &mut Vec<Op>) -> u32 {
+    let bytes = offset.wrapping_mul(width.bytes() as i32);
+    ops.push(Op::LocalGet(POINTER));
+    match bytes {
+        0.. => bytes as u32,
+        _ => {
+            ops.extend([Op::I32Const(bytes.unsigned_abs() as i32), Op::I32Sub]);
+            0
+        }
+    }
+}
+
+/// Helper: add a value to the cell at an offset from the pointer, or subtract it with `Op::Sub`
+fn update_cell(offset: i32, value: u64, op: Op, width: CellWidth, ops: &mut Vec<Op>) {
+    let memory_offset = cell_address(offset, width, ops);
+    cell_address(offset, width, ops);
+    ops.extend([Op::Load(width, memory_offset), Op::Const(width, value), op]);
+    ops.push(Op::Store(width, memory_offset));
+}
+
+/// Helper: print the cell at an offset from the pointer
+fn output(offset: i32, width: CellWidth, ops: &mut Vec<Op>) {
+    let memory_offset = cell_address(offset, width, ops);
+    ops.push(Op::Load(width, memory_offset));
+    if width == CellWidth::U64 {
+        ops.push(Op::I32WrapI64);
+    }
+    ops.push(Op::Call(PUTCHAR));
+}
+
+/// Helper: trap unless the cell at `offset` bytes from the pointer is on a tape of `tape_bytes` bytes.
+/// The pointer is always on the tape, so the comparison never overflows.
+fn check_bounds(offset: i64, tape_bytes: u32, ops: &mut Vec<Op>) {
+    let (limit, compare) = match offset {
+        0 => return,
+        // Offsets past the end of the tape always trap
+        1.. => (
+            tape_bytes as i64 - offset.min(tape_bytes as i64),
+            Op::I32GeU,
+        ),
+        _ => (
+            offset.unsigned_abs().min(tape_bytes as u64) as i64,
+            Op::I32LtU,
+        ),
+    };
+    ops.extend([
+        Op::LocalGet(POINTER),
+        Op::I32Const(limit as u32 as i32),
+        compare,
+        Op::If,
+        Op::Unreachable,
+        Op::End,
+    ]);
+}
+
+/// Helper: value type of the cells of the given width
+fn value_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U64 => "i64",
+        _ => "i32",
+    }
+}
+
+/// Helper: text of an instruction
+fn op_text(op: &Op) -> String {
+    // Memory offsets are only printed when they are not 0
+    let offset = |offset: u32| match offset {
+        0 => String::new(),
+        _ => format!(" offset={offset}"),
+    };
+
+    match *op {
+        Op::Block => "block".to_string(),
+        Op::Loop => "loop".to_string(),
+        Op::If => "if".to_string(),
+        Op::End => "end".to_string(),
+        Op::Unreachable => "unreachable".to_string(),
+        Op::Br(depth) => format!("br {depth}"),
+        Op::BrIf(depth) => format!("br_if {depth}"),
+        Op::Call(function) => format!("call {}", FUNCTION_NAMES[function as usize]),
+        Op::Select => "select".to_string(),
+        Op::LocalGet(local) => format!("local.get {}", LOCAL_NAMES[local as usize]),
+        Op::LocalSet(local) => format!("local.set {}", LOCAL_NAMES[local as usize]),
+        Op::LocalTee(local) => format!("local.tee {}", LOCAL_NAMES[local as usize]),
+        Op::I32Const(value) => format!("i32.const {value}"),
+        Op::I32Add => "i32.add".to_string(),
+        Op::I32Sub => "i32.sub".to_string(),
+        Op::I32GeS => "i32.ge_s".to_string(),
+        Op::I32GeU => "i32.ge_u".to_string(),
+        Op::I32LtU => "i32.lt_u".to_string(),
+        Op::I32WrapI64 => "i32.wrap_i64".to_string(),
+        Op::I64ExtendI32U => "i64.extend_i32_u".to_string(),
+        Op::Load(width, memory_offset) => {
+            let load = match width {
+                CellWidth::U8 => "i32.load8_u",
+                CellWidth::U16 => "i32.load16_u",
+                CellWidth::U32 => "i32.load",
+                CellWidth::U64 => "i64.load",
+            };
+            format!("{load}{}", offset(memory_offset))
+        }
+        Op::Store(width, memory_offset) => {
+            let store = match width {
+                CellWidth::U8 => "i32.store8",
+                CellWidth::U16 => "i32.store16",
+                CellWidth::U32 => "i32.store",
+                CellWidth::U64 => "i64.store",
+            };
+            format!("{store}{}", offset(memory_offset))
+        }
+        Op::Const(CellWidth::U64, value) => format!("i64.const {}", value as i64),
+        Op::Const(_, value) => format!("i32.const {}", value as u32 as i32),
+        Op::Add(width) => format!("{}.add", value_type(width)),
+        Op::Sub(width) => format!("{}.sub", value_type(width)),
+        Op::Mul(width) => format!("{}.mul", value_type(width)),
+        Op::Eqz(width) => format!("{}.eqz", value_type(width)),
+    }
+}
+
+/// Helper: binary encoding of an instruction
+fn op_binary(op: &Op) -> Vec<u8> {
+    // 64-bit operations have their own opcodes
+    let wide = |width: CellWidth, narrow: u8, wide: u8| match width {
+        CellWidth::U64 => wide,
+        _ => narrow,
+    };
+    // Alignment (log2 of the cell size) and offset of a memory access
+    let memory_argument = |width: CellWidth, offset: u32| {
+        let mut bytes = vec![width.bytes().trailing_zeros() as u8];
+        bytes.extend(unsigned(offset as u64));
+        bytes
+    };
+
+    let (opcode, immediate) = match *op {
+        Op::Block => (0x02, vec![0x40]), // without result
+        Op::Loop => (0x03, vec![0x40]),
+        Op::If => (0x04, vec![0x40]),
+        Op::End => (0x0b, vec![]),
+        Op::Unreachable => (0x00, vec![]),
+        Op::Br(depth) => (0x0c, unsigned(depth as u64)),
+        Op::BrIf(depth) => (0x0d, unsigned(depth as u64)),
+        Op::Call(function) => (0x10, unsigned(function as u64)),
+        Op::Select => (0x1b, vec![]),
+        Op::LocalGet(local) => (0x20, unsigned(local as u64)),
+        Op::LocalSet(local) => (0x21, unsigned(local as u64)),
+        Op::LocalTee(local) => (0x22, unsigned(local as u64)),
+        Op::I32Const(value) => (0x41, signed(value as i64)),
+        Op::I32Add => (0x6a, vec![]),
+        Op::I32Sub => (0x6b, vec![]),
+        Op::I32GeS => (0x4e, vec![]),
+        Op::I32GeU => (0x4f, vec![]),
+        Op::I32LtU => (0x49, vec![]),
+        Op::I32WrapI64 => (0xa7, vec![]),
+        Op::I64ExtendI32U => (0xad, vec![]),
+        Op::Load(width, offset) => {
+            let opcode = match width {
+                CellWidth::U8 => 0x2d,
+                CellWidth::U16 => 0x2f,
+                CellWidth::U32 => 0x28,
+                CellWidth::U64 => 0x29,
+            };
+            (opcode, memory_argument(width, offset))
+        }
+        Op::Store(width, offset) => {
+            let opcode = match width {
+                CellWidth::U8 => 0x3a,
+                CellWidth::U16 => 0x3b,
+                CellWidth::U32 => 0x36,
+                CellWidth::U64 => 0x37,
+            };
+            (opcode, memory_argument(width, offset))
+        }
+        Op::Const(CellWidth::U64, value) => (0x42, signed(value as i64)),
+        Op::Const(_, value) => (0x41, signed(value as u32 as i32 as i64)),
+        Op::Add(width) => (wide(width, 0x6a, 0x7c), vec![]),
+        Op::Sub(width) => (wide(width, 0x6b, 0x7d), vec![]),
+        Op::Mul(width) => (wide(width, 0x6c, 0x7e), vec![]),
+        Op::Eqz(width) => (wide(width, 0x45, 0x50), vec![]),
+    };
+
+    let mut bytes = vec![opcode];
+    bytes.extend(immediate);
+    bytes
+}
+
+/// Helper: section with the given id, prefixed with its size
+fn section(id: u8, contents: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![id];
+    bytes.extend(unsigned(contents.len() as u64));
+    bytes.extend(contents);
+    bytes
+}
+
+/// Helper: name, prefixed with its length
+fn name(name: &str) -> Vec<u8> {
+    let mut bytes = unsigned(name.len() as u64);
+    bytes.extend_from_slice(name.as_bytes());
+    bytes
+}
+
+/// Helper: unsigned LEB128 encoding
+fn unsigned(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Helper: signed LEB128 encoding
+fn signed(mut value: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // Done when the remaining bits are all copies of the sign bit of this byte
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
@@ -1,11 +1,132 @@
 //! Machine code helper
 //!
-//! This module implements From and Into (implicit) for all instructions in order to produce machine code.
+//! This module encodes instructions into machine code with [`encode`], for a given cell width.
+//! It also implements From and Into (implicit) for all instructions, producing machine code for 8-bit cells.
+//!
+//! Registers used by the generated code:
+//! - `r13` points to the current cell
+//! - `r12` holds the context pointer passed as first argument to the compiled function
+//! - `r14` and `r15` hold the start and end addresses of the tape, for bounds checks
+//! - `rbx` holds the size of the tape, for wraparound
+//!
+//! They are all callee-saved, so they are preserved across calls to the host I/O callbacks.
+//!
+//! Prologue - save the callee-saved registers and load the context and tape pointers
+//!
+//! Pushing 5 registers also realigns the stack on 16 bytes, as required for calls.
+//! ```asm
+//! push rbx        ; 0x53
+//! push r12        ; 0x41 0x54
+//! push r13        ; 0x41 0x55
+//! push r14        ; 0x41 0x56
+//! push r15        ; 0x41 0x57
+//! mov r12, rdi    ; 0x49 0x89 0xfc
+//! mov r13, start  ; 0x49 0xbd start (8 bytes)
+//! mov r14, start  ; 0x49 0xbe start (8 bytes)
+//! mov r15, end    ; 0x49 0xbf end (8 bytes)
+//! mov rbx, size   ; 0x48 0xbb size (8 bytes)
+//! ```
+//!
+//! Prologue (object files) - same, with the tape passed as first argument instead of the context
+//!
+//! The code only holds relative offsets, and can be linked at any address.
+//! ```asm
+//! push rbx                ; 0x53
+//! push r12                ; 0x41 0x54
+//! push r13                ; 0x41 0x55
+//! push r14                ; 0x41 0x56
+//! push r15                ; 0x41 0x57
+//! mov r13, rdi            ; 0x49 0x89 0xfd
+//! mov r14, rdi            ; 0x49 0x89 0xfe
+//! mov rbx, size           ; 0x48 0xbb size (8 bytes)
+//! lea r15, [rdi + rbx]    ; 0x4c 0x8d 0x3c 0x1f
+//! ```
+//!
+//! Epilogue - restore the callee-saved registers and return
+//! ```asm
+//! pop r15         ; 0x41 0x5f
+//! pop r14         ; 0x41 0x5e
+//! pop r13         ; 0x41 0x5d
+//! pop r12         ; 0x41 0x5c
+//! pop rbx         ; 0x5b
+//! ret             ; 0xc3
+//! ```
+//!
+//! Bounds check - jump to a fault stub if the tape pointer left the tape (safe mode only)
+//! ```asm
+//! cmp r13, r14    ; 0x4d 0x39 0xf5
+//! jb fault        ; 0x0f 0x82 0xff 0xff 0xff 0xff
+//! cmp r13, r15    ; 0x4d 0x39 0xfd
+//! jae fault       ; 0x0f 0x83 0xff 0xff 0xff 0xff
+//! ```
+//! The `0xff` placeholders will need to be replaced with the offset of the fault stub.
+//!
+//! Wraparound - move the tape pointer to the other end of the tape if it left the tape
+//!
+//! This is only correct for moves smaller than the tape size.
+//! ```asm
+//! cmp r13, r15    ; 0x4d 0x39 0xfd
+//! jb low          ; 0x72 0x03
+//! sub r13, rbx    ; 0x49 0x29 0xdd
+//! low:
+//! cmp r13, r14    ; 0x4d 0x39 0xf5
+//! jae done        ; 0x73 0x03
+//! add r13, rbx    ; 0x49 0x01 0xdd
+//! done:
+//! ```
+//!
+//! Fault stub - record the tape pointer and the index of the faulty instruction in the context
+//! (at offsets 32 and 24), and exit
+//! ```asm
+//! mov [r12 + 32], r13             ; 0x4d 0x89 0x6c 0x24 0x20
+//! mov qword ptr [r12 + 24], index ; 0x49 0xc7 0x44 0x24 0x18 index (4 bytes)
+//! jmp epilogue                    ; 0xe9 0xff 0xff 0xff 0xff
+//! ```
 //!
 //! Some details about the more complex calls:
 //!
-//! Output - print the current cell - syscall to `print`
+//! Output (callback) - call `output(context, value)` on the host
+//! ```asm
+//! mov rdi, r12                ; 0x4c 0x89 0xe7
+//! movzx esi, byte ptr [r13]   ; 0x41 0x0f 0xb6 0x75 0x00
+//! mov rax, output             ; 0x48 0xb8 output (8 bytes)
+//! call rax                    ; 0xff 0xd0
+//! ```
+//!
+//! Output (buffered) - append the current cell to the context output buffer, and call `flush(context)` when it is full
+//!
+//! The context starts with the buffer pointer, its length and its capacity (3 x 8 bytes).
+//! ```asm
+//! mov rax, [r12 + 8]          ; 0x49 0x8b 0x44 0x24 0x08
+//! mov rcx, [r12]              ; 0x49 0x8b 0x0c 0x24
+//! movzx edx, byte ptr [r13]   ; 0x41 0x0f 0xb6 0x55 0x00
+//! mov [rcx + rax], dl         ; 0x88 0x14 0x01
+//! inc rax                     ; 0x48 0xff 0xc0
+//! mov [r12 + 8], rax          ; 0x49 0x89 0x44 0x24 0x08
+//! cmp rax, [r12 + 16]         ; 0x49 0x3b 0x44 0x24 0x10
+//! jb done                     ; 0x72 0x0f
+//! mov rdi, r12                ; 0x4c 0x89 0xe7
+//! mov rax, flush              ; 0x48 0xb8 flush (8 bytes)
+//! call rax                    ; 0xff 0xd0
+//! done:
+//! ```
+//!
+//! Input (callback) - call `input(context, value)` on the host, which returns the new cell value
+//!
+//! The host never accesses the tape itself, so that guard page faults only happen in compiled code.
+//! The cell is loaded and stored with its own width (see below): here with 8-bit cells.
+//! ```asm
+//! mov rdi, r12                ; 0x4c 0x89 0xe7
+//! movzx esi, byte ptr [r13]   ; 0x41 0x0f 0xb6 0x75 0x00
+//! mov rax, input              ; 0x48 0xb8 input (8 bytes)
+//! call rax                    ; 0xff 0xd0
+//! mov byte ptr [r13], al      ; 0x41 0x88 0x45 0x00
 //! ```
+//!
+//! The instructions below do not need a host: they only rely on raw syscalls to stdin and stdout.
+//!
+//! Output (syscall) - print the current cell - syscall to `print`
+//! ```asm
 //! mov rax, 1      ; 0x48 0xc7 0xc0 0x01 0x00 0x00 0x00
 //! mov rdi, 1      ; 0x48 0xc7 0xc7 0x01 0x00 0x00 0x00
 //! mov rsi, r13    ; 0x4c 0x89 0xee
@@ -13,54 +134,580 @@
 //! syscall         ; 0x0f 0x05
 //! ```
 //!
+//! Output value (syscall) - print a byte known at compile time, from the stack
+//! ```asm
+//! push value      ; 0x6a value
+//! mov rax, 1      ; 0x48 0xc7 0xc0 0x01 0x00 0x00 0x00
+//! mov rdi, 1      ; 0x48 0xc7 0xc7 0x01 0x00 0x00 0x00
+//! mov rsi, rsp    ; 0x48 0x89 0xe6
+//! mov rdx, 1      ; 0x48 0xc7 0xc2 0x01 0x00 0x00 0x00
+//! syscall         ; 0x0f 0x05
+//! pop rcx         ; 0x59
+//! ```
+//!
+//! Input (syscall) - read one byte from stdin into the current cell - syscall to `read`
+//!
+//! On end of input, `read` returns 0 and leaves the current cell unchanged.
+//! ```asm
+//! mov rax, 0      ; 0x48 0xc7 0xc0 0x00 0x00 0x00 0x00
+//! mov rdi, 0      ; 0x48 0xc7 0xc7 0x00 0x00 0x00 0x00
+//! mov rsi, r13    ; 0x4c 0x89 0xee
+//! mov rdx, 1      ; 0x48 0xc7 0xc2 0x01 0x00 0x00 0x00
+//! syscall         ; 0x0f 0x05
+//! ```
+//!
+//! Depending on the [`EofBehavior`], the cell is then overwritten if nothing was read
+//! (`rax` is 0 at end of input, and negative on error).
+//! ```asm
+//! test rax, rax           ; 0x48 0x85 0xc0
+//! jg done                 ; 0x7f 0x05
+//! mov byte ptr [r13], x   ; 0x41 0xc6 0x45 0x00 x
+//! done:
+//! ```
+//!
+//! With wider cells, the byte read is then zero-extended over the whole cell (here with 32-bit cells).
+//! ```asm
+//! test rax, rax               ; 0x48 0x85 0xc0
+//! jle eof                     ; 0x7e 0x0b
+//! movzx eax, byte ptr [r13]   ; 0x41 0x0f 0xb6 0x45 0x00
+//! mov dword ptr [r13], eax    ; 0x41 0x89 0x45 0x00
+//! jmp done                    ; 0xeb 0x08
+//! eof:
+//! mov dword ptr [r13], x      ; 0x41 0xc7 0x45 0x00 x (4 bytes)
+//! done:
+//! ```
+//!
+//! Exit (standalone executables) - end the process with status 0, instead of returning to the host
+//! ```asm
+//! mov eax, 60     ; 0xb8 0x3c 0x00 0x00 0x00
+//! xor edi, edi    ; 0x31 0xff
+//! syscall         ; 0x0f 0x05
+//! ```
+//!
 //! Jump Forward - jump to the matching `]` if the current cell is 0
 //!
-//! We put the value of the current cell `[r13]` into the `rax` register so that we can access its lower byte using `al`.
-//! We can then set the jump flags using `test`, and add the jump instruction.
+//! We compare the current cell `[r13]` to 0 in order to set the jump flags, and add the jump instruction.
 //! We use `0xff` as placeholder addresses, that will be resolved during the second pass.
 //!
-//! Note that the `jz` instruction accepts a signed 64-bit offset (8 bytes) as an argument.
-//! ```
-//! mov rax, [r13]  ; 0x49 0x8B 0x45 0x00
-//! test al, al     ; 0x84 0xc0
-//! jz xxx          ; 0x0f 0x84 0xff 0xff 0xff 0xff
+//! Note that the `jz` instruction accepts a signed 32-bit offset (4 bytes) as an argument.
+//! ```asm
+//! cmp byte ptr [r13], 0   ; 0x41 0x80 0x7d 0x00 0x00
+//! jz xxx                  ; 0x0f 0x84 0xff 0xff 0xff 0xff
 //! ```
 //! The last `0xff` instructions will need to be replaced with the actual offset.
 //!
 //! Jump Backwards - jump to the matching `[` if the current cell is not 0
+//! ```asm
+//! cmp byte ptr [r13], 0   ; 0x41 0x80 0x7d 0x00 0x00
+//! jnz xxx                 ; 0x0f 0x85 0xff 0xff 0xff 0xff
+//! ```
+//! The last `0xff` instructions will need to be replaced with the actual offset.
 //!
-//! We put the value of the current cell `[r13]` into the `rax` register so that we can access its lower byte using `al`.
-//! We can then set the jump flags using `test`, and add the jump instruction.
-//! We use `0xff` as placeholder addresses, that will be resolved during the second pass.
+//! Multiply and add - add the current cell times a factor to the cell at some offset, if the current cell is not 0
 //!
-//! Note that the `jnz` instruction accepts a signed 64-bit offset (8 bytes) as an argument.
+//! The target cell is only accessed when the current cell is not 0, like in the loop this replaces.
+//! Its address is computed in `rcx`, so that it can be checked or wrapped around like the tape pointer.
+//! ```asm
+//! movzx eax, byte ptr [r13]   ; 0x41 0x0f 0xb6 0x45 0x00
+//! test rax, rax               ; 0x48 0x85 0xc0
+//! jz done                     ; 0x0f 0x84 0xff 0xff 0xff 0xff
+//! lea rcx, [r13 + offset]     ; 0x49 0x8d 0x8d offset (4 bytes)
+//! imul rax, rax, factor       ; 0x48 0x69 0xc0 factor (4 bytes)
+//! add byte ptr [rcx], al      ; 0x00 0x01
+//! done:
 //! ```
-//! mov rax, [r13]  ; 0x49 0x8B 0x45 0x00
-//! test al, al     ; 0x84 0xc0
-//! jnz xxx          ; 0x0f 0x85 0xff 0xff 0xff 0xff
+//!
+//! Scan right - move the pointer right until the current cell is 0, 16 bytes at a time (8-bit cells here)
+//!
+//! Cells are first checked one by one until the pointer is aligned on 16 bytes. Aligned loads never cross
+//! a page boundary, so they only touch the pages that the loop they replace would have touched.
+//! ```asm
+//! pxor xmm0, xmm0             ; 0x66 0x0f 0xef 0xc0
+//! align:
+//! cmp byte ptr [r13], 0       ; 0x41 0x80 0x7d 0x00 0x00
+//! je done                     ; 0x74 0x27
+//! inc r13                     ; 0x49 0xff 0xc5
+//! test r13b, 15               ; 0x41 0xf6 0xc5 0x0f
+//! jnz align                   ; 0x75 0xf0
+//! chunk:
+//! movdqa xmm1, [r13]          ; 0x66 0x41 0x0f 0x6f 0x4d 0x00
+//! pcmpeqb xmm1, xmm0          ; 0x66 0x0f 0x74 0xc8
+//! pmovmskb eax, xmm1          ; 0x66 0x0f 0xd7 0xc1
+//! test eax, eax               ; 0x85 0xc0
+//! jnz found                   ; 0x75 0x06
+//! add r13, 16                 ; 0x49 0x83 0xc5 0x10
+//! jmp chunk                   ; 0xeb 0xe8
+//! found:
+//! bsf eax, eax                ; 0x0f 0xbc 0xc0
+//! add r13, rax                ; 0x49 0x01 0xc5
+//! done:
 //! ```
-//! The last `0xff` instructions will need to be replaced with the actual offset.
+//!
+//! Scan left works the same way, with the pointer on the last cell of each chunk:
+//! ```asm
+//! pxor xmm0, xmm0             ; 0x66 0x0f 0xef 0xc0
+//! align:
+//! cmp byte ptr [r13], 0       ; 0x41 0x80 0x7d 0x00 0x00
+//! je done                     ; 0x74 0x2b
+//! dec r13                     ; 0x49 0xff 0xcd
+//! lea eax, [r13 + 1]          ; 0x41 0x8d 0x45 0x01
+//! test al, 15                 ; 0xa8 0x0f
+//! jnz align                   ; 0x75 0xee
+//! chunk:
+//! movdqa xmm1, [r13 - 15]     ; 0x66 0x41 0x0f 0x6f 0x4d 0xf1
+//! pcmpeqb xmm1, xmm0          ; 0x66 0x0f 0x74 0xc8
+//! pmovmskb eax, xmm1          ; 0x66 0x0f 0xd7 0xc1
+//! test eax, eax               ; 0x85 0xc0
+//! jnz found                   ; 0x75 0x06
+//! sub r13, 16                 ; 0x49 0x83 0xed 0x10
+//! jmp chunk                   ; 0xeb 0xe8
+//! found:
+//! bsr eax, eax                ; 0x0f 0xbd 0xc0
+//! lea r13, [r13 + rax - 15]   ; 0x4d 0x8d 0x6c 0x05 0xf1
+//! done:
+//! ```
+//! 16 and 32-bit cells use `pcmpeqw` (0x75) and `pcmpeqd` (0x76). Other scans are plain loops, like `[>>]`.
+//!
+//! Cell width - instructions operating on a cell use the operand size of the cell
+//!
+//! The `0x41` REX prefix selects `r13` as the base register. Wider cells use a different prefix and opcode:
+//! ```asm
+//! inc byte ptr [r13]      ; 0x41 0xfe 0x45 0x00
+//! inc word ptr [r13]      ; 0x66 0x41 0xff 0x45 0x00
+//! inc dword ptr [r13]     ; 0x41 0xff 0x45 0x00
+//! inc qword ptr [r13]     ; 0x49 0xff 0x45 0x00
+//! ```
+//! Immediates have the size of the cell, except for 64-bit cells where they are sign-extended from 32 bits.
+//! Cells at an offset from the current one use a displacement, on 1 byte or 4 bytes (ModRM `0x85`):
+//! ```asm
+//! add byte ptr [r13 + 2], 5   ; 0x41 0x80 0x45 0x02 0x05
+//! ```
+//! Larger 64-bit values first go through `rax`:
+//! ```asm
+//! mov rax, value          ; 0x48 0xb8 value (8 bytes)
+//! add qword ptr [r13], rax ; 0x49 0x01 0x45 0x00
+//! ```
+
+use crate::{
+    config::{CellWidth, EofBehavior},
+    instructions::{ExtendedInstruction, Instruction},
+};
+
+/// Function prologue: save registers, and load the context (first argument) and tape pointers
+pub fn prologue(tape_start: *const u8, tape_end: *const u8) -> Vec<u8> {
+    let mut bytes = vec![0x53, 0x41, 0x54, 0x41, 0x55]; // push rbx | push r12 | push r13
+    bytes.extend_from_slice(&[0x41, 0x56, 0x41, 0x57]); // push r14 | push r15
+    bytes.extend_from_slice(&[0x49, 0x89, 0xfc]); // mov r12, rdi
+    bytes.extend_from_slice(&[0x49, 0xbd]); // mov r13, start
+    bytes.extend_from_slice(&(tape_start as u64).to_le_bytes());
+    bytes.extend_from_slice(&[0x49, 0xbe]); // mov r14, start
+    bytes.extend_from_slice(&(tape_start as u64).to_le_bytes());
+    bytes.extend_from_slice(&[0x49, 0xbf]); // mov r15, end
+    bytes.extend_from_slice(&(tape_end as u64).to_le_bytes());
+    bytes.extend_from_slice(&[0x48, 0xbb]); // mov rbx, size
+    bytes.extend_from_slice(&(tape_end as u64 - tape_start as u64).to_le_bytes());
+    bytes
+}
+
+/// Function prologue for object files: save registers, and load the tape of `size` bytes passed as first argument
+pub fn object_prologue(size: u64) -> Vec<u8> {
+    let mut bytes = vec![0x53, 0x41, 0x54, 0x41, 0x55]; // push rbx | push r12 | push r13
+    bytes.extend_from_slice(&[0x41, 0x56, 0x41, 0x57]); // push r14 | push r15
+    bytes.extend_from_slice(&[0x49, 0x89, 0xfd]); // mov r13, rdi
+    bytes.extend_from_slice(&[0x49, 0x89, 0xfe]); // mov r14, rdi
+    bytes.extend_from_slice(&[0x48, 0xbb]); // mov rbx, size
+    bytes.extend_from_slice(&size.to_le_bytes());
+    bytes.extend_from_slice(&[0x4c, 0x8d, 0x3c, 0x1f]); // lea r15, [rdi + rbx]
+    bytes
+}
+
+/// Function epilogue: restore registers and return
+pub fn epilogue() -> Vec<u8> {
+    let mut bytes = vec![0x41, 0x5f, 0x41, 0x5e]; // pop r15 | pop r14
+    bytes.extend_from_slice(&[0x41, 0x5d, 0x41, 0x5c, 0x5b]); // pop r13 | pop r12 | pop rbx
+    bytes.push(0xc3); // ret
+    bytes
+}
+
+/// End the process with status 0, in place of the epilogue of standalone executables
+pub fn exit() -> Vec<u8> {
+    vec![0xb8, 0x3c, 0x00, 0x00, 0x00, 0x31, 0xff, 0x0f, 0x05] // mov eax, 60 | xor edi, edi | syscall
+}
+
+/// Check that the tape pointer is inside the tape, jumping to a fault stub otherwise.
+/// The two jump offsets end at bytes 9 and 18, and need to be patched.
+pub fn bounds_check() -> Vec<u8> {
+    let mut bytes = vec![0x4d, 0x39, 0xf5]; // cmp r13, r14
+    bytes.extend_from_slice(&[0x0f, 0x82, 0xff, 0xff, 0xff, 0xff]); // jb fault
+    bytes.extend_from_slice(&[0x4d, 0x39, 0xfd]); // cmp r13, r15
+    bytes.extend_from_slice(&[0x0f, 0x83, 0xff, 0xff, 0xff, 0xff]); // jae fault
+    bytes
+}
+
+/// Same as [`bounds_check`], for the cell address in `rcx`
+pub fn address_bounds_check() -> Vec<u8> {
+    let mut bytes = vec![0x4c, 0x39, 0xf1]; // cmp rcx, r14
+    bytes.extend_from_slice(&[0x0f, 0x82, 0xff, 0xff, 0xff, 0xff]); // jb fault
+    bytes.extend_from_slice(&[0x4c, 0x39, 0xf9]); // cmp rcx, r15
+    bytes.extend_from_slice(&[0x0f, 0x83, 0xff, 0xff, 0xff, 0xff]); // jae fault
+    bytes
+}
+
+/// Move the tape pointer to the other end of the tape if it left the tape, by less than the tape size
+pub fn wraparound() -> Vec<u8> {
+    let mut bytes = vec![0x4d, 0x39, 0xfd, 0x72, 0x03]; // cmp r13, r15 | jb low
+    bytes.extend_from_slice(&[0x49, 0x29, 0xdd]); // sub r13, rbx
+    bytes.extend_from_slice(&[0x4d, 0x39, 0xf5, 0x73, 0x03]); // cmp r13, r14 | jae done
+    bytes.extend_from_slice(&[0x49, 0x01, 0xdd]); // add r13, rbx
+    bytes
+}
+
+/// Same as [`wraparound`], for the cell address in `rcx`
+pub fn address_wraparound() -> Vec<u8> {
+    let mut bytes = vec![0x4c, 0x39, 0xf9, 0x72, 0x03]; // cmp rcx, r15 | jb low
+    bytes.extend_from_slice(&[0x48, 0x29, 0xd9]); // sub rcx, rbx
+    bytes.extend_from_slice(&[0x4c, 0x39, 0xf1, 0x73, 0x03]); // cmp rcx, r14 | jae done
+    bytes.extend_from_slice(&[0x48, 0x01, 0xd9]); // add rcx, rbx
+    bytes
+}
+
+/// Record the tape pointer and the index of the faulty instruction in the context, and jump to the epilogue.
+/// The jump offset ends at byte 19, and needs to be patched.
+pub fn bounds_fault(index: u32) -> Vec<u8> {
+    let mut bytes = vec![0x4d, 0x89, 0x6c, 0x24, 0x20]; // mov [r12 + 32], r13
+    bytes.extend_from_slice(&[0x49, 0xc7, 0x44, 0x24, 0x18]); // mov qword ptr [r12 + 24], index
+    bytes.extend_from_slice(&index.to_le_bytes());
+    bytes.extend_from_slice(&[0xe9, 0xff, 0xff, 0xff, 0xff]); // jmp epilogue
+    bytes
+}
+
+/// Machine code for the `.` instruction, calling `output(context, value)` on the host with the cell at `offset` bytes
+pub fn output_callback(output: *const (), offset: i32) -> Vec<u8> {
+    let mut load = vec![0x41, 0x0f, 0xb6]; // movzx esi, byte ptr [r13 + offset]
+    load.extend(cell_operand(6, offset));
+    call_output(output, &load)
+}
+
+/// Machine code for outputting a known byte, calling `output(context, value)` on the host
+pub fn output_value_callback(output: *const (), value: u8) -> Vec<u8> {
+    let mut load = vec![0xbe]; // mov esi, value
+    load.extend_from_slice(&(value as u32).to_le_bytes());
+    call_output(output, &load)
+}
+
+/// Machine code for the `.` instruction, appending the cell at `offset` bytes to the context output buffer
+/// and calling `flush(context)` when full
+pub fn buffered_output(flush: *const (), offset: i32) -> Vec<u8> {
+    let mut store = vec![0x41, 0x0f, 0xb6]; // movzx edx, byte ptr [r13 + offset]
+    store.extend(cell_operand(2, offset));
+    store.extend_from_slice(&[0x88, 0x14, 0x01]); // mov [rcx + rax], dl
+    append_output(flush, &store)
+}
+
+/// Machine code for outputting a known byte, appending it to the context output buffer
+/// and calling `flush(context)` when full
+pub fn buffered_output_value(flush: *const (), value: u8) -> Vec<u8> {
+    append_output(flush, &[0xc6, 0x04, 0x01, value]) // mov byte ptr [rcx + rax], value
+}
+
+/// Machine code for the `,` instruction, calling `input(context, value)` on the host and storing the result
+pub fn input_callback(input: *const (), width: CellWidth) -> Vec<u8> {
+    let mut bytes = vec![0x4c, 0x89, 0xe7]; // mov rdi, r12
+    bytes.extend(match width {
+        CellWidth::U8 => vec![0x41, 0x0f, 0xb6, 0x75, 0x00], // movzx esi, byte ptr [r13]
+        CellWidth::U16 => vec![0x41, 0x0f, 0xb7, 0x75, 0x00], // movzx esi, word ptr [r13]
+        CellWidth::U32 => vec![0x41, 0x8b, 0x75, 0x00],      // mov esi, dword ptr [r13]
+        CellWidth::U64 => vec![0x49, 0x8b, 0x75, 0x00],      // mov rsi, qword ptr [r13]
+    });
+    bytes.extend_from_slice(&[0x48, 0xb8]); // mov rax, input
+    bytes.extend_from_slice(&(input as u64).to_le_bytes());
+    bytes.extend_from_slice(&[0xff, 0xd0]); // call rax
+    bytes.extend(cell_opcode(width, 0x88, 0x89)); // mov [r13], al / ax / eax / rax
+    bytes.extend_from_slice(&[0x45, 0x00]);
+    bytes
+}
+
+/// Machine code for the `,` instruction using a raw `read` syscall, taking the end of input behavior into account.
+/// The byte read is stored in the low byte of the cell, and then zero-extended to the cell width.
+pub fn input(eof_behavior: EofBehavior, width: CellWidth) -> Vec<u8> {
+    let mut bytes: Vec<u8> = (&Instruction::Input).into();
+
+    let widen = match width {
+        CellWidth::U8 => Vec::new(),
+        _ => {
+            let mut widen = vec![0x41, 0x0f, 0xb6, 0x45, 0x00]; // movzx eax, byte ptr [r13]
+            widen.extend(cell_opcode(width, 0x88, 0x89)); // mov [r13], ax / eax / rax
+            widen.extend_from_slice(&[0x45, 0x00]);
+            widen
+        }
+    };
+    let eof = match eof_behavior.value(width) {
+        Some(value) => store(width, 0, value),
+        None => Vec::new(),
+    };
+
+    match (widen.is_empty(), eof.is_empty()) {
+        (true, true) => {}
+        (true, false) => {
+            bytes.extend_from_slice(&[0x48, 0x85, 0xc0, 0x7f, eof.len() as u8]); // test rax, rax | jg done
+            bytes.extend(eof);
+        }
+        (false, true) => {
+            bytes.extend_from_slice(&[0x48, 0x85, 0xc0, 0x7e, widen.len() as u8]); // test rax, rax | jle done
+            bytes.extend(widen);
+        }
+        (false, false) => {
+            let skip = widen.len() as u8 + 2;
+            bytes.extend_from_slice(&[0x48, 0x85, 0xc0, 0x7e, skip]); // test rax, rax | jle eof
+            bytes.extend(widen);
+            bytes.extend_from_slice(&[0xeb, eof.len() as u8]); // jmp done
+            bytes.extend(eof);
+        }
+    }
+
+    bytes
+}
+
+/// Load the current cell in `rax`, and skip the following code if it is 0.
+/// The jump offset ends with the machine code, and needs to be patched.
+pub fn skip_if_zero(width: CellWidth) -> Vec<u8> {
+    let mut bytes = match width {
+        CellWidth::U8 => vec![0x41, 0x0f, 0xb6, 0x45, 0x00], // movzx eax, byte ptr [r13]
+        CellWidth::U16 => vec![0x41, 0x0f, 0xb7, 0x45, 0x00], // movzx eax, word ptr [r13]
+        CellWidth::U32 => vec![0x41, 0x8b, 0x45, 0x00],      // mov eax, dword ptr [r13]
+        CellWidth::U64 => vec![0x49, 0x8b, 0x45, 0x00],      // mov rax, qword ptr [r13]
+    };
+    bytes.extend_from_slice(&[0x48, 0x85, 0xc0]); // test rax, rax
+    bytes.extend_from_slice(&[0x0f, 0x84, 0xff, 0xff, 0xff, 0xff]); // jz done
+    bytes
+}
+
+/// Offset in bytes of the cell at `offset` cells from the current one
+pub fn displacement(offset: i32, width: CellWidth) -> i32 {
+    i32::try_from(offset as i64 * width.bytes() as i64)
+        .expect("Cell offsets fit in a 32-bit displacement")
+}
+
+/// Load the address of the cell at `offset` bytes from the current one in `rcx`
+pub fn cell_address(offset: i64) -> Vec<u8> {
+    match i32::try_from(offset) {
+        Ok(offset) => {
+            let mut bytes = vec![0x49, 0x8d, 0x8d]; // lea rcx, [r13 + offset]
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes
+        }
+        Err(_) => {
+            let mut bytes = vec![0x48, 0xb9]; // mov rcx, offset
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&[0x4c, 0x01, 0xe9]); // add rcx, r13
+            bytes
+        }
+    }
+}
+
+/// Multiply `rax` (the current cell) by `factor`, and add it to the cell at the address in `rcx`
+pub fn mul_add(factor: u64, width: CellWidth) -> Vec<u8> {
+    let factor = factor & width.mask();
+    let mut bytes = Vec::new();
+
+    // Only the low bits of the product matter: the factor can be sign-extended for narrow cells
+    let signed = match width {
+        CellWidth::U64 => factor as i64,
+        _ if factor > width.mask() / 2 => factor as i64 - width.mask() as i64 - 1,
+        _ => factor as i64,
+    };
+    match i32::try_from(signed) {
+        Ok(1) => {}
+        Ok(factor) => {
+            bytes.extend_from_slice(&[0x48, 0x69, 0xc0]); // imul rax, rax, factor
+            bytes.extend_from_slice(&factor.to_le_bytes());
+        }
+        Err(_) => {
+            bytes.extend_from_slice(&[0x48, 0xba]); // mov rdx, factor
+            bytes.extend_from_slice(&factor.to_le_bytes());
+            bytes.extend_from_slice(&[0x48, 0x0f, 0xaf, 0xc2]); // imul rax, rdx
+        }
+    }
+
+    bytes.extend(match width {
+        CellWidth::U8 => vec![0x00, 0x01],        // add byte ptr [rcx], al
+        CellWidth::U16 => vec![0x66, 0x01, 0x01], // add word ptr [rcx], ax
+        CellWidth::U32 => vec![0x01, 0x01],       // add dword ptr [rcx], eax
+        CellWidth::U64 => vec![0x48, 0x01, 0x01], // add qword ptr [rcx], rax
+    });
+    bytes
+}
+
+/// Move the pointer right until the current cell is 0, comparing 16 bytes at a time.
+/// Returns `None` for 64-bit cells.
+pub fn scan_right(width: CellWidth) -> Option<Vec<u8>> {
+    let size = width.bytes() as i64;
+    let mut bytes = vec![0x66, 0x0f, 0xef, 0xc0]; // pxor xmm0, xmm0
 
-use crate::instructions::{ExtendedInstruction, Instruction};
+    let align = bytes.len();
+    bytes.extend(compare_zero(width));
+    bytes.extend_from_slice(&[0x74, 0x00]); // je done
+    let to_done = bytes.len();
+    bytes.extend(move_pointer(size));
+    bytes.extend_from_slice(&[0x41, 0xf6, 0xc5, 0x0f]); // test r13b, 15
+    bytes.extend(short_jump(0x75, bytes.len(), align)); // jnz align
+
+    let chunk = bytes.len();
+    bytes.extend_from_slice(&[0x66, 0x41, 0x0f, 0x6f, 0x4d, 0x00]); // movdqa xmm1, [r13]
+    bytes.extend(compare_equal(width)?); // pcmpeq xmm1, xmm0
+    bytes.extend_from_slice(&[0x66, 0x0f, 0xd7, 0xc1]); // pmovmskb eax, xmm1
+    bytes.extend_from_slice(&[0x85, 0xc0, 0x75, 0x06]); // test eax, eax | jnz found
+    bytes.extend_from_slice(&[0x49, 0x83, 0xc5, 0x10]); // add r13, 16
+    bytes.extend(short_jump(0xeb, bytes.len(), chunk)); // jmp chunk
+
+    bytes.extend_from_slice(&[0x0f, 0xbc, 0xc0]); // bsf eax, eax
+    bytes.extend_from_slice(&[0x49, 0x01, 0xc5]); // add r13, rax
+    bytes[to_done - 1] = (bytes.len() - to_done) as u8;
+
+    Some(bytes)
+}
+
+/// Move the pointer left until the current cell is 0, comparing 16 bytes at a time.
+/// Returns `None` for 64-bit cells.
+pub fn scan_left(width: CellWidth) -> Option<Vec<u8>> {
+    let size = width.bytes() as i64;
+    let mut bytes = vec![0x66, 0x0f, 0xef, 0xc0]; // pxor xmm0, xmm0
+
+    let align = bytes.len();
+    bytes.extend(compare_zero(width));
+    bytes.extend_from_slice(&[0x74, 0x00]); // je done
+    let to_done = bytes.len();
+    bytes.extend(move_pointer(-size));
+    bytes.extend_from_slice(&[0x41, 0x8d, 0x45, size as u8]); // lea eax, [r13 + size]
+    bytes.extend_from_slice(&[0xa8, 0x0f]); // test al, 15
+    bytes.extend(short_jump(0x75, bytes.len(), align)); // jnz align
+
+    let chunk = bytes.len();
+    bytes.extend_from_slice(&[0x66, 0x41, 0x0f, 0x6f, 0x4d, (size - 16) as u8]); // movdqa xmm1, [r13 + size - 16]
+    bytes.extend(compare_equal(width)?); // pcmpeq xmm1, xmm0
+    bytes.extend_from_slice(&[0x66, 0x0f, 0xd7, 0xc1]); // pmovmskb eax, xmm1
+    bytes.extend_from_slice(&[0x85, 0xc0, 0x75, 0x06]); // test eax, eax | jnz found
+    bytes.extend_from_slice(&[0x49, 0x83, 0xed, 0x10]); // sub r13, 16
+    bytes.extend(short_jump(0xeb, bytes.len(), chunk)); // jmp chunk
+
+    // bsr finds the last byte of the cell
+    bytes.extend_from_slice(&[0x0f, 0xbd, 0xc0]); // bsr eax, eax
+    bytes.extend_from_slice(&[0x4d, 0x8d, 0x6c, 0x05, 0xf1]); // lea r13, [r13 + rax - 15]
+    bytes[to_done - 1] = (bytes.len() - to_done) as u8;
+
+    Some(bytes)
+}
+
+/// Move the pointer by `step` bytes until the current cell is 0, one cell at a time.
+/// The `check` code runs after each move: it is inserted right before the final 5-byte jump.
+pub fn scan_loop(step: i64, width: CellWidth, check: &[u8]) -> Vec<u8> {
+    let mut bytes = compare_zero(width);
+    bytes.extend_from_slice(&[0x0f, 0x84, 0xff, 0xff, 0xff, 0xff]); // jz done
+    let to_done = bytes.len();
+    bytes.extend(move_pointer(step));
+    bytes.extend_from_slice(check);
+    bytes.extend_from_slice(&[0xe9, 0xff, 0xff, 0xff, 0xff]); // jmp start
+
+    let end = bytes.len();
+    bytes[end - 4..].copy_from_slice(&(-(end as i32)).to_le_bytes());
+    bytes[to_done - 4..to_done].copy_from_slice(&((end - to_done) as i32).to_le_bytes());
+    bytes
+}
+
+/// Machine code for an extended instruction, operating on cells of the given width.
+/// `.` and `,` use raw syscalls.
+pub fn encode(instruction: &ExtendedInstruction, width: CellWidth) -> Vec<u8> {
+    let size = width.bytes() as i64;
+
+    match instruction {
+        ExtendedInstruction::Regular(Instruction::MoveRight) => move_pointer(size),
+        ExtendedInstruction::Regular(Instruction::MoveLeft) => move_pointer(-size),
+        ExtendedInstruction::Regular(Instruction::Increment) => {
+            let mut bytes = cell_opcode(width, 0xfe, 0xff);
+            bytes.extend_from_slice(&[0x45, 0x00]); // inc [r13]
+            bytes
+        }
+        ExtendedInstruction::Regular(Instruction::Decrement) => {
+            let mut bytes = cell_opcode(width, 0xfe, 0xff);
+            bytes.extend_from_slice(&[0x4d, 0x00]); // dec [r13]
+            bytes
+        }
+        ExtendedInstruction::Regular(Instruction::JumpForward) => {
+            let mut bytes = compare_zero(width);
+            bytes.extend_from_slice(&[0x0f, 0x84, 0xff, 0xff, 0xff, 0xff]); // jz xxx
+            bytes
+        }
+        ExtendedInstruction::Regular(Instruction::JumpBackwards) => {
+            let mut bytes = compare_zero(width);
+            bytes.extend_from_slice(&[0x0f, 0x85, 0xff, 0xff, 0xff, 0xff]); // jnz xxx
+            bytes
+        }
+        ExtendedInstruction::Regular(instruction) => instruction.into(),
+        ExtendedInstruction::Add { value, offset } => {
+            arithmetic(width, 0, 0x01, displacement(*offset, width), *value) // add [r13 + offset], value
+        }
+        ExtendedInstruction::Sub { value, offset } => {
+            arithmetic(width, 5, 0x29, displacement(*offset, width), *value) // sub [r13 + offset], value
+        }
+        ExtendedInstruction::JumpLeft(offset) => move_pointer(-(*offset as i64) * size),
+        ExtendedInstruction::JumpRight(offset) => move_pointer(*offset as i64 * size),
+        ExtendedInstruction::SetZero { offset } => store(width, displacement(*offset, width), 0), // mov [r13 + offset], 0
+        ExtendedInstruction::SetValue { value, offset } => {
+            store(width, displacement(*offset, width), *value) // mov [r13 + offset], value
+        }
+        ExtendedInstruction::Output { offset: 0 } => (&Instruction::Output).into(),
+        ExtendedInstruction::Output { offset } => {
+            let mut bytes: Vec<u8> = (&Instruction::Output).into();
+            bytes.splice(
+                14..17,
+                [0x49, 0x8d]
+                    .into_iter()
+                    .chain(cell_operand(6, displacement(*offset, width))),
+            );
+            bytes // lea rsi, [r13 + offset] instead of mov rsi, r13
+        }
+        ExtendedInstruction::OutputValue(value) => {
+            let mut output: Vec<u8> = (&Instruction::Output).into();
+            output.splice(14..17, [0x48, 0x89, 0xe6]); // mov rsi, rsp instead of mov rsi, r13
+
+            let mut bytes = vec![0x6a, *value]; // push value
+            bytes.extend(output);
+            bytes.push(0x59); // pop rcx
+            bytes
+        }
+        ExtendedInstruction::MulAdd { offset, factor } => {
+            let mut bytes = skip_if_zero(width);
+            let skip = bytes.len();
+            bytes.extend(cell_address(*offset as i64 * size));
+            bytes.extend(mul_add(*factor, width));
+            let done = bytes.len();
+            bytes[skip - 4..skip].copy_from_slice(&((done - skip) as i32).to_le_bytes());
+            bytes
+        }
+        ExtendedInstruction::ScanRight(1) => {
+            scan_right(width).unwrap_or_else(|| scan_loop(size, width, &[]))
+        }
+        ExtendedInstruction::ScanLeft(1) => {
+            scan_left(width).unwrap_or_else(|| scan_loop(-size, width, &[]))
+        }
+        ExtendedInstruction::ScanRight(stride) => scan_loop(*stride as i64 * size, width, &[]),
+        ExtendedInstruction::ScanLeft(stride) => scan_loop(-(*stride as i64) * size, width, &[]),
+    }
+}
 
 /// Implement conversion from basic instructions to machine code
 impl From<&Instruction> for Vec<u8> {
     fn from(instruction: &Instruction) -> Self {
         match instruction {
-            Instruction::MoveRight => vec![0x49, 0xff, 0xc5], // inc r13
-            Instruction::MoveLeft => vec![0x49, 0xff, 0xcd],  // dec r13
-            Instruction::Increment => vec![0x41, 0xfe, 0x45, 0x00], // inc byte ptr [r13] (size u8)
-            Instruction::Decrement => vec![0x41, 0xfe, 0x4d, 0x00], // dec byte ptr [r13] (size u8)
-            Instruction::JumpBackwards => vec![
-                0x49, 0x8b, 0x45, 0x00, 0x84, 0xc0, 0x0f, 0x85, 0xff, 0xff, 0xff, 0xff,
-            ],
-            Instruction::JumpForward => vec![
-                0x49, 0x8b, 0x45, 0x00, 0x84, 0xc0, 0x0f, 0x84, 0xff, 0xff, 0xff, 0xff,
-            ],
             Instruction::Output => vec![
                 0x48, 0xc7, 0xc0, 0x01, 0x00, 0x00, 0x00, 0x48, 0xc7, 0xc7, 0x01, 0x00, 0x00, 0x00,
                 0x4c, 0x89, 0xee, 0x48, 0xc7, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x0f, 0x05,
             ], // mov rax, 1 | mov rdi, 1 | mov rsi, r13 | mov rdx, 1 | syscall (ie: print [r13])
+            Instruction::Input => vec![
+                0x48, 0xc7, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x48, 0xc7, 0xc7, 0x00, 0x00, 0x00, 0x00,
+                0x4c, 0x89, 0xee, 0x48, 0xc7, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x0f, 0x05,
+            ], // mov rax, 0 | mov rdi, 0 | mov rsi, r13 | mov rdx, 1 | syscall (ie: read [r13])
+            _ => encode(&ExtendedInstruction::Regular(*instruction), CellWidth::U8),
         }
     }
 }
@@ -68,45 +715,166 @@ impl From<&Instruction> for Vec<u8> {
 /// Implement conversion from extended instructions to machine code
 impl From<&ExtendedInstruction> for Vec<u8> {
     fn from(instruction: &ExtendedInstruction) -> Self {
-        match instruction {
-            ExtendedInstruction::Regular(instruction) => instruction.into(),
-            ExtendedInstruction::Add(count) => vec![
-                0x41, 0x83, 0x45, 0x00, *count, // add byte ptr [r13], count
-            ],
-            ExtendedInstruction::Sub(count) => vec![
-                0x41, 0x80, 0x6d, 0x00, *count, // sub byte ptr [r13], count
-            ],
-            ExtendedInstruction::JumpLeft(offset) => {
-                let mut bytes = Vec::new();
-
-                if *offset <= 0xff {
-                    // sub with 1 byte format
-                    bytes.extend_from_slice(&[0x49, 0x83, 0xed]);
-                    bytes.push(*offset as u8);
-                } else {
-                    // sub with 4 bytes format
-                    bytes.extend_from_slice(&[0x49, 0x81, 0xed]);
-                    bytes.extend(&offset.to_be_bytes());
-                }
-
-                bytes
-            } // sub r13, offset
-            ExtendedInstruction::JumpRight(offset) => {
-                let mut bytes = Vec::new();
-
-                if *offset <= 0xff {
-                    // add with 1 byte format
-                    bytes.extend_from_slice(&[0x49, 0x83, 0xC5]);
-                    bytes.push(*offset as u8);
-                } else {
-                    // add with 4 bytes format
-                    bytes.extend_from_slice(&[0x49, 0x81, 0xC5]);
-                    bytes.extend(&offset.to_be_bytes());
-                }
-
-                bytes
-            } // add r13, offset
-            ExtendedInstruction::SetZero => vec![0x41, 0xC6, 0x45, 0x00, 0x00], // mov byte ptr [r13], 0
+        encode(instruction, CellWidth::U8)
+    }
+}
+
+// ********************************************************************************************* //
+//                                           HELPER FUNCTIONS                                    //
+// ********************************************************************************************* //
+
+/// Helper: operand size prefixes and opcode of an instruction operating on the current cell `[r13]`.
+/// `opcode_8` is the opcode for byte operands, and `opcode` the one for larger operands.
+fn cell_opcode(width: CellWidth, opcode_8: u8, opcode: u8) -> Vec<u8> {
+    match width {
+        CellWidth::U8 => vec![0x41, opcode_8],
+        CellWidth::U16 => vec![0x66, 0x41, opcode],
+        CellWidth::U32 => vec![0x41, opcode],
+        CellWidth::U64 => vec![0x49, opcode],
+    }
+}
+
+/// Helper: immediate of the cell size, or `None` for 64-bit values that do not fit in a sign-extended 32-bit immediate
+fn immediate(width: CellWidth, value: u64) -> Option<Vec<u8>> {
+    let value = value & width.mask();
+
+    match width {
+        CellWidth::U8 => Some(vec![value as u8]),
+        CellWidth::U16 => Some((value as u16).to_le_bytes().to_vec()),
+        CellWidth::U32 => Some((value as u32).to_le_bytes().to_vec()),
+        CellWidth::U64 => i32::try_from(value as i64)
+            .ok()
+            .map(|value| value.to_le_bytes().to_vec()),
+    }
+}
+
+/// Helper: `cmp [r13], 0`
+fn compare_zero(width: CellWidth) -> Vec<u8> {
+    let mut bytes = cell_opcode(width, 0x80, 0x83); // 0x83: the immediate is a sign-extended byte
+    bytes.extend_from_slice(&[0x7d, 0x00, 0x00]);
+    bytes
+}
+
+/// Helper: `pcmpeqb`, `pcmpeqw` or `pcmpeqd xmm1, xmm0`, or `None` for 64-bit cells (`pcmpeqq` requires SSE4.1)
+fn compare_equal(width: CellWidth) -> Option<Vec<u8>> {
+    let opcode = match width {
+        CellWidth::U8 => 0x74,
+        CellWidth::U16 => 0x75,
+        CellWidth::U32 => 0x76,
+        CellWidth::U64 => return None,
+    };
+    Some(vec![0x66, 0x0f, opcode, 0xc8])
+}
+
+/// Helper: short jump (2 bytes, with the given opcode) starting at index `from`, to index `to`
+fn short_jump(opcode: u8, from: usize, to: usize) -> Vec<u8> {
+    vec![opcode, (to as isize - from as isize - 2) as i8 as u8]
+}
+
+/// Helper: ModRM byte and displacement of the memory operand `[r13 + offset]`, with the given register field
+fn cell_operand(register: u8, offset: i32) -> Vec<u8> {
+    match i8::try_from(offset) {
+        Ok(offset) => vec![0x45 | register << 3, offset as u8],
+        Err(_) => {
+            let mut bytes = vec![0x85 | register << 3];
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes
+        }
+    }
+}
+
+/// Helper: add or sub (selected by the ModRM register field, or the register form opcode for large 64-bit values)
+/// a constant to the cell at `offset` bytes
+fn arithmetic(
+    width: CellWidth,
+    register: u8,
+    register_opcode: u8,
+    offset: i32,
+    value: u64,
+) -> Vec<u8> {
+    match immediate(width, value) {
+        Some(immediate) => {
+            let mut bytes = cell_opcode(width, 0x80, 0x81);
+            bytes.extend(cell_operand(register, offset));
+            bytes.extend(immediate);
+            bytes
+        }
+        None => {
+            let mut bytes = vec![0x48, 0xb8]; // mov rax, value
+            bytes.extend_from_slice(&value.to_le_bytes());
+            bytes.extend_from_slice(&[0x49, register_opcode]); // op qword ptr [r13 + offset], rax
+            bytes.extend(cell_operand(0, offset));
+            bytes
+        }
+    }
+}
+
+/// Helper: `output(context, value)` host call, given the code loading the value in `esi`
+fn call_output(output: *const (), load: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x4c, 0x89, 0xe7]; // mov rdi, r12
+    bytes.extend_from_slice(load);
+    bytes.extend_from_slice(&[0x48, 0xb8]); // mov rax, output
+    bytes.extend_from_slice(&(output as u64).to_le_bytes());
+    bytes.extend_from_slice(&[0xff, 0xd0]); // call rax
+    bytes
+}
+
+/// Helper: append a byte to the context output buffer, given the code storing it at `[rcx + rax]`
+fn append_output(flush: *const (), store: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x49, 0x8b, 0x44, 0x24, 0x08]; // mov rax, [r12 + 8]
+    bytes.extend_from_slice(&[0x49, 0x8b, 0x0c, 0x24]); // mov rcx, [r12]
+    bytes.extend_from_slice(store);
+    bytes.extend_from_slice(&[0x48, 0xff, 0xc0]); // inc rax
+    bytes.extend_from_slice(&[0x49, 0x89, 0x44, 0x24, 0x08]); // mov [r12 + 8], rax
+    bytes.extend_from_slice(&[0x49, 0x3b, 0x44, 0x24, 0x10]); // cmp rax, [r12 + 16]
+    bytes.extend_from_slice(&[0x72, 0x0f]); // jb done
+    bytes.extend_from_slice(&[0x4c, 0x89, 0xe7]); // mov rdi, r12
+    bytes.extend_from_slice(&[0x48, 0xb8]); // mov rax, flush
+    bytes.extend_from_slice(&(flush as u64).to_le_bytes());
+    bytes.extend_from_slice(&[0xff, 0xd0]); // call rax
+    bytes
+}
+
+/// Helper: `mov [r13 + offset], value`
+fn store(width: CellWidth, offset: i32, value: u64) -> Vec<u8> {
+    match immediate(width, value) {
+        Some(immediate) => {
+            let mut bytes = cell_opcode(width, 0xc6, 0xc7);
+            bytes.extend(cell_operand(0, offset));
+            bytes.extend(immediate);
+            bytes
+        }
+        None => {
+            let mut bytes = vec![0x48, 0xb8]; // mov rax, value
+            bytes.extend_from_slice(&value.to_le_bytes());
+            bytes.extend_from_slice(&[0x49, 0x89]); // mov qword ptr [r13 + offset], rax
+            bytes.extend(cell_operand(0, offset));
+            bytes
         }
     }
 }
+
+/// Helper: `add r13, offset` (in bytes)
+fn move_pointer(offset: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    if offset == 1 {
+        bytes.extend_from_slice(&[0x49, 0xff, 0xc5]); // inc r13
+    } else if offset == -1 {
+        bytes.extend_from_slice(&[0x49, 0xff, 0xcd]); // dec r13
+    } else if let Ok(offset) = i8::try_from(offset) {
+        // add with 1 byte format (the immediate is sign-extended)
+        bytes.extend_from_slice(&[0x49, 0x83, 0xc5]);
+        bytes.push(offset as u8);
+    } else if let Ok(offset) = i32::try_from(offset) {
+        // add with 4 bytes format (the immediate is sign-extended)
+        bytes.extend_from_slice(&[0x49, 0x81, 0xc5]);
+        bytes.extend(&offset.to_le_bytes());
+    } else {
+        bytes.extend_from_slice(&[0x48, 0xb8]); // mov rax, offset
+        bytes.extend(&offset.to_le_bytes());
+        bytes.extend_from_slice(&[0x49, 0x01, 0xc5]); // add r13, rax
+    }
+
+    bytes
+}
@@ -0,0 +1,89 @@
+//! Unbalanced brackets are reported with the span of the offending bracket, by every backend
+
+use lib::{
+    compiler::Compiler,
+    error::BfError,
+    interpreter::Interpreter,
+    lexer::{tokenize_all_with_spans, Span},
+    passes::PassManager,
+    transpiler::{Target, Transpiler},
+};
+
+/// Error of every way to run or translate a program, at the given optimization level
+fn errors(source: &str, level: u8) -> Vec<BfError> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let passes = PassManager::with_level(level);
+
+    let mut interpreter = Interpreter::new()
+        .with_output(Vec::new())
+        .with_passes(passes.clone());
+    let mut compiler = Compiler::new()
+        .with_output(Vec::new())
+        .with_passes(passes.clone());
+    let mut transpiler = Transpiler::new().with_passes(passes);
+
+    let results = [
+        interpreter.execute(&program),
+        compiler.compile(&program),
+        compiler.compile_executable(&program).map(drop),
+        compiler.compile_object(&program, "bf_main").map(drop),
+        transpiler.transpile(&program, Target::C).map(drop),
+        transpiler.transpile(&program, Target::Rust).map(drop),
+        transpiler.transpile(&program, Target::Wat).map(drop),
+        transpiler.compile_wasm(&program).map(drop),
+    ];
+    results
+        .into_iter()
+        .map(|result| result.expect_err("unbalanced brackets are an error"))
+        .collect()
+}
+
+/// Assert that every backend reports the same error, at every optimization level
+fn assert_error(source: &str, expected: fn(Span) -> BfError, span: Span) {
+    for level in 0..=3 {
+        for error in errors(source, level) {
+            let expected = expected(span);
+            assert_eq!(
+                format!("{error:?}"),
+                format!("{expected:?}"),
+                "{source:?} at -O{level}"
+            );
+        }
+    }
+}
+
+fn span(offset: usize, line: usize, column: usize) -> Span {
+    Span {
+        offset,
+        line,
+        column,
+        len: 1,
+    }
+}
+
+#[test]
+fn unmatched_close_bracket() {
+    assert_error("+[-]]", BfError::UnmatchedCloseBracket, span(4, 1, 5));
+    // The first unmatched `]` is reported, even with an unmatched `[` after it
+    assert_error("+\n  ]\n[", BfError::UnmatchedCloseBracket, span(4, 2, 3));
+}
+
+#[test]
+fn unmatched_open_bracket() {
+    assert_error("[[-]", BfError::UnmatchedOpenBracket, span(0, 1, 1));
+    // The first unmatched `[` is reported, even with more of them after it
+    assert_error(
+        "+[-]\ncomment [>[<",
+        BfError::UnmatchedOpenBracket,
+        span(13, 2, 9),
+    );
+}
+
+#[test]
+fn bracket_errors_are_displayed_with_their_position() {
+    let error = errors("+\n  ]", 0).remove(0);
+    assert_eq!(
+        error.to_string(),
+        "unmatched ']' at line 2, column 3 (byte 4)"
+    );
+}
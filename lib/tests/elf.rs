@@ -0,0 +1,221 @@
+//! Ahead-of-time compilation: ELF headers and sections, and the programs they run
+
+use std::{
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use lib::{
+    compiler::Compiler,
+    config::{CellWidth, TapeConfig},
+    elf::{entry_address, tape_address, BASE_ADDRESS},
+    interpreter::Interpreter,
+    lexer::tokenize_all_with_spans,
+};
+
+/// Echoes its input in upper case, up to a newline
+const PROGRAM: &str = ",----------[----------------------.,----------]";
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Fields of a section header, as read from a file
+#[derive(Debug)]
+struct Section {
+    name: String,
+    kind: u32,
+    flags: u64,
+    address: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+}
+
+/// Check the ELF header fields common to all files, and return the section headers
+fn sections(file: &[u8], file_type: u16) -> Vec<Section> {
+    assert_eq!(file[..8], [0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    assert_eq!(u16_at(file, 16), file_type);
+    assert_eq!(u16_at(file, 18), 0x3e); // x86-64
+    assert_eq!(u16_at(file, 52), 64); // header size
+
+    let offset = u64_at(file, 40) as usize;
+    let count = u16_at(file, 60) as usize;
+    let headers: Vec<&[u8]> = (0..count)
+        .map(|index| &file[offset + 64 * index..offset + 64 * (index + 1)])
+        .collect();
+    let names = &headers[u16_at(file, 62) as usize];
+    let names = &file[u64_at(names, 24) as usize..];
+
+    headers
+        .iter()
+        .map(|header| {
+            let name = &names[u32_at(header, 0) as usize..];
+            let name = &name[..name.iter().position(|byte| *byte == 0).unwrap()];
+            Section {
+                name: String::from_utf8(name.to_vec()).unwrap(),
+                kind: u32_at(header, 4),
+                flags: u64_at(header, 8),
+                address: u64_at(header, 16),
+                offset: u64_at(header, 24) as usize,
+                size: u64_at(header, 32) as usize,
+                link: u32_at(header, 40),
+                info: u32_at(header, 44),
+            }
+        })
+        .collect()
+}
+
+/// Temporary file path, unique to this test process
+fn temporary(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bf-elf-{}-{name}", std::process::id()))
+}
+
+/// Output of a program run with the given input
+fn run(path: &Path, input: &[u8]) -> Vec<u8> {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}: {}",
+        path.display(),
+        output.status
+    );
+    output.stdout
+}
+
+/// Output of the program run by the interpreter
+fn interpret(input: &[u8]) -> Vec<u8> {
+    let program = tokenize_all_with_spans(PROGRAM.as_bytes().to_vec());
+    let mut interpreter = Interpreter::new().with_input(input).with_output(Vec::new());
+    interpreter.execute(&program).unwrap();
+    interpreter.into_output()
+}
+
+#[test]
+fn executables_load_code_and_tape_segments() {
+    let program = tokenize_all_with_spans(PROGRAM.as_bytes().to_vec());
+    let tape = TapeConfig {
+        size: 1000,
+        ..TapeConfig::default()
+    };
+    let mut compiler = Compiler::new()
+        .with_tape(tape)
+        .with_cell_width(CellWidth::U32);
+    let file = compiler.compile_executable(&program).unwrap();
+
+    let sections = sections(&file, 2);
+    let names: Vec<&str> = sections
+        .iter()
+        .map(|section| section.name.as_str())
+        .collect();
+    assert_eq!(names, ["", ".text", ".bss", ".shstrtab"]);
+    let (text, bss) = (&sections[1], &sections[2]);
+    assert_eq!(text.address, entry_address());
+    assert_eq!(text.address, BASE_ADDRESS + text.offset as u64);
+    assert_eq!(bss.address, tape_address(text.size));
+    assert_eq!(bss.address % 0x1000, 0);
+    assert_eq!((bss.kind, bss.flags, bss.size), (8, 3, 4000));
+
+    // Entry point, then two loadable segments: the headers and code, and the tape
+    assert_eq!(u64_at(&file, 24), entry_address());
+    assert_eq!(u16_at(&file, 56), 2);
+    let segment = |index: usize| {
+        let header = &file[u64_at(&file, 32) as usize + 56 * index..];
+        let fields = [8, 16, 32, 40].map(|offset| u64_at(header, offset));
+        (u32_at(header, 0), u32_at(header, 4), fields)
+    };
+    let code_end = (text.offset + text.size) as u64;
+    assert_eq!(segment(0), (1, 5, [0, BASE_ADDRESS, code_end, code_end]));
+    assert_eq!(segment(1), (1, 6, [0, bss.address, 0, 4000]));
+
+    let path = temporary("executable");
+    std::fs::write(&path, &file).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = run(&path, b"hello\n");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output, b"HELLO");
+    assert_eq!(output, interpret(b"hello\n"));
+}
+
+#[test]
+fn objects_define_a_global_function() {
+    let program = tokenize_all_with_spans(PROGRAM.as_bytes().to_vec());
+    let mut compiler = Compiler::new();
+    let file = compiler.compile_object(&program, "shout").unwrap();
+
+    assert_eq!(u16_at(&file, 56), 0); // no program headers
+    let sections = sections(&file, 1);
+    let names: Vec<&str> = sections
+        .iter()
+        .map(|section| section.name.as_str())
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "",
+            ".text",
+            ".symtab",
+            ".strtab",
+            ".shstrtab",
+            ".note.GNU-stack"
+        ]
+    );
+    let (text, symtab, strtab) = (&sections[1], &sections[2], &sections[3]);
+    assert_eq!((text.kind, text.flags), (1, 6));
+    assert_eq!((symtab.kind, symtab.link, symtab.info), (2, 3, 1));
+
+    // The global symbol: a function in .text covering all the code
+    let symbol = &file[symtab.offset + 24..symtab.offset + 48];
+    let name = &file[strtab.offset + u32_at(symbol, 0) as usize..];
+    assert!(name.starts_with(b"shout\0"));
+    assert_eq!(symbol[4], 0x12); // STB_GLOBAL, STT_FUNC
+    assert_eq!(u16_at(symbol, 6), 1); // .text
+    assert_eq!(
+        (u64_at(symbol, 8), u64_at(symbol, 16)),
+        (0, text.size as u64)
+    );
+
+    // Link it with a C program providing the tape
+    let object = temporary("shout.o");
+    let main = temporary("main.c");
+    let executable = temporary("main");
+    std::fs::write(&object, &file).unwrap();
+    std::fs::write(
+        &main,
+        "#include <stdint.h>\n\
+         void shout(uint8_t *tape);\n\
+         static uint8_t tape[30000];\n\
+         int main(void) { shout(tape); return 0; }\n",
+    )
+    .unwrap();
+    let status = Command::new("cc")
+        .arg(&main)
+        .arg(&object)
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = run(&executable, b"linked\n");
+    for path in [object, main, executable] {
+        std::fs::remove_file(path).unwrap();
+    }
+    assert_eq!(output, b"LINKED");
+}
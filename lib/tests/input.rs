@@ -0,0 +1,70 @@
+//! Input: `,` reads bytes, and stores the configured value at end of input
+
+use lib::{
+    compiler::Compiler,
+    config::{CellWidth, EofBehavior},
+    interpreter::Interpreter,
+    lexer::tokenize_all_with_spans,
+};
+
+const WIDTHS: [CellWidth; 4] = [
+    CellWidth::U8,
+    CellWidth::U16,
+    CellWidth::U32,
+    CellWidth::U64,
+];
+
+/// First cells left by a program run by the interpreter
+fn interpret(source: &str, input: &[u8], width: CellWidth, eof_behavior: EofBehavior) -> Vec<u64> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let mut interpreter = Interpreter::new()
+        .with_input(input)
+        .with_output(Vec::new())
+        .with_cell_width(width)
+        .with_eof_behavior(eof_behavior);
+    interpreter.execute(&program).unwrap();
+    interpreter.tape()[..3].to_vec()
+}
+
+/// First cells left by a program run by the JIT compiler
+fn compile(source: &str, input: &[u8], width: CellWidth, eof_behavior: EofBehavior) -> Vec<u64> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let mut compiler = Compiler::new()
+        .with_input(input)
+        .with_output(Vec::new())
+        .with_cell_width(width)
+        .with_eof_behavior(eof_behavior);
+    compiler.compile(&program).unwrap();
+    compiler.execute().unwrap();
+    compiler.tape()[..3].to_vec()
+}
+
+#[test]
+fn input_reads_bytes() {
+    for width in WIDTHS {
+        for run in [interpret, compile] {
+            assert_eq!(
+                run(",>,>,", b"ab\xff", width, EofBehavior::Zero),
+                [97, 98, 255]
+            );
+        }
+    }
+}
+
+#[test]
+fn input_at_end_stores_the_eof_value() {
+    for width in WIDTHS {
+        let cases = [
+            (EofBehavior::Unchanged, 3),
+            (EofBehavior::Zero, 0),
+            (EofBehavior::MinusOne, width.mask()),
+        ];
+        for (eof_behavior, eof) in cases {
+            for run in [interpret, compile] {
+                // The first byte is read, and the next cells hit the end of input
+                let tape = run(",>+++,>+++,", b"a", width, eof_behavior);
+                assert_eq!(tape, [97, eof, eof], "{width:?}, {eof_behavior:?}");
+            }
+        }
+    }
+}
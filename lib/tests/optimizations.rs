@@ -0,0 +1,235 @@
+//! Property tests: optimized programs must leave the same tape and output as unoptimized ones
+
+use lib::{
+    compiler::Compiler,
+    config::{CellWidth, TapeConfig},
+    interpreter::Interpreter,
+    lexer::tokenize_all_with_spans,
+    passes::PassManager,
+};
+use proptest::prelude::*;
+
+/// A piece of generated brainfuck code
+#[derive(Debug, Clone)]
+enum Op {
+    /// A run of `+` (positive) or `-` (negative)
+    Add(i16),
+    /// A run of `>` (positive) or `<` (negative)
+    Move(i8),
+    Output,
+    Input,
+    /// `[>]` with the given stride
+    Scan(u8),
+    /// `[+]` or `[-]` with a run of the given odd length, which always clears the cell
+    Clear(i16),
+    /// A loop decrementing its counter once per iteration, so that it always ends
+    Loop(Vec<Op>),
+}
+
+/// Largest position of the pointer, well inside the default tape
+const MAX_POSITION: i64 = 200;
+
+/// Render generated code to brainfuck source, keeping the pointer on the tape.
+/// `position` is a lower bound of the pointer position, exact relative to the enclosing loops.
+/// `counters` holds the positions of the enclosing loop counters, which the code must not modify.
+fn render(ops: &[Op], source: &mut String, position: &mut i64, counters: &mut Vec<i64>) {
+    for op in ops {
+        match op {
+            Op::Add(n) if !counters.contains(position) => {
+                let symbol = if *n > 0 { "+" } else { "-" };
+                source.push_str(&symbol.repeat(n.unsigned_abs() as usize));
+            }
+            Op::Move(n) => {
+                let target = (*position + *n as i64).clamp(0, MAX_POSITION);
+                move_to(source, position, target);
+            }
+            Op::Output => source.push('.'),
+            Op::Input if !counters.contains(position) => source.push(','),
+            // Scans only happen outside of loops, which must move the pointer back to their counter
+            Op::Scan(stride) if counters.is_empty() => {
+                source.push_str(&format!("[{}]", ">".repeat(*stride as usize)));
+            }
+            Op::Clear(n) if !counters.contains(position) => {
+                let symbol = if *n > 0 { "+" } else { "-" };
+                let count = n.unsigned_abs() as usize | 1;
+                source.push_str(&format!("[{}]", symbol.repeat(count)));
+            }
+            Op::Loop(body) if !counters.contains(position) => {
+                let counter = *position;
+                source.push('[');
+                counters.push(counter);
+                render(body, source, position, counters);
+                counters.pop();
+                move_to(source, position, counter);
+                source.push_str("-]");
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Move the pointer to the given position
+fn move_to(source: &mut String, position: &mut i64, target: i64) {
+    let symbol = if target > *position { ">" } else { "<" };
+    source.push_str(&symbol.repeat((target - *position).unsigned_abs() as usize));
+    *position = target;
+}
+
+/// Generated code, with loops nested at most twice.
+/// Wider cells only count up by small steps and are cleared with `[-]`, so that loops end quickly.
+fn program(width: CellWidth) -> impl Strategy<Value = String> {
+    let (adds, clears) = match width {
+        CellWidth::U8 => (-600i16..600, -300i16..300),
+        _ => (0..4, -1..0),
+    };
+    let leaf = prop_oneof![
+        adds.prop_map(Op::Add),
+        (-4i8..=4).prop_map(Op::Move),
+        Just(Op::Output),
+        Just(Op::Input),
+        (1u8..=3).prop_map(Op::Scan),
+        clears.prop_map(Op::Clear),
+    ];
+    let op = leaf.prop_recursive(2, 32, 8, |inner| {
+        prop::collection::vec(inner, 0..8).prop_map(Op::Loop)
+    });
+
+    prop::collection::vec(op, 0..24).prop_map(|ops| {
+        let mut source = String::new();
+        render(&ops, &mut source, &mut 0, &mut Vec::new());
+        source
+    })
+}
+
+/// Straight-line code with long runs of `+` and `-`
+fn straight_line_program() -> impl Strategy<Value = String> {
+    let op = prop_oneof![
+        (-1000i16..1000).prop_map(Op::Add),
+        (-4i8..=4).prop_map(Op::Move),
+        Just(Op::Output),
+    ];
+
+    prop::collection::vec(op, 0..32).prop_map(|ops| {
+        let mut source = String::new();
+        render(&ops, &mut source, &mut 0, &mut Vec::new());
+        source
+    })
+}
+
+fn cell_width() -> impl Strategy<Value = CellWidth> {
+    prop_oneof![
+        Just(CellWidth::U8),
+        Just(CellWidth::U16),
+        Just(CellWidth::U32),
+        Just(CellWidth::U64),
+    ]
+}
+
+/// Tape configuration the programs run with
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Default,
+    /// A tape smaller than the largest pointer position, which wraps around
+    Wrap,
+    /// A tape smaller than the largest pointer position, which grows (the JIT rounds it up to a page)
+    Grow,
+    /// The default tape, surrounded by guard pages in the JIT
+    Guard,
+}
+
+impl Mode {
+    fn tape(self) -> TapeConfig {
+        match self {
+            Mode::Default | Mode::Guard => TapeConfig::default(),
+            Mode::Wrap | Mode::Grow => TapeConfig {
+                size: 64,
+                growable: matches!(self, Mode::Grow),
+                wrapping: matches!(self, Mode::Wrap),
+            },
+        }
+    }
+}
+
+fn mode() -> impl Strategy<Value = Mode> {
+    prop_oneof![
+        Just(Mode::Default),
+        Just(Mode::Wrap),
+        Just(Mode::Grow),
+        Just(Mode::Guard),
+    ]
+}
+
+/// Tape and output of a program run by the interpreter
+fn interpret(
+    source: &str,
+    input: &[u8],
+    width: CellWidth,
+    mode: Mode,
+    passes: PassManager,
+) -> (Vec<u64>, Vec<u8>) {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let mut interpreter = Interpreter::new()
+        .with_input(input)
+        .with_output(Vec::new())
+        .with_tape(mode.tape())
+        .with_cell_width(width)
+        .with_passes(passes);
+    interpreter.execute(&program).unwrap();
+    (trim(interpreter.tape()), interpreter.into_output())
+}
+
+/// Tape and output of a program run by the JIT compiler
+fn compile(
+    source: &str,
+    input: &[u8],
+    width: CellWidth,
+    mode: Mode,
+    passes: PassManager,
+) -> (Vec<u64>, Vec<u8>) {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let mut compiler = Compiler::new()
+        .with_input(input)
+        .with_output(Vec::new())
+        .with_tape(mode.tape())
+        .with_guard_pages(matches!(mode, Mode::Guard))
+        .with_cell_width(width)
+        .with_passes(passes);
+    compiler.compile(&program).unwrap();
+    compiler.execute().unwrap();
+    (trim(&compiler.tape()), compiler.into_output())
+}
+
+/// Cells of a tape, without the trailing zeros
+fn trim(tape: &[u64]) -> Vec<u64> {
+    let len = tape
+        .iter()
+        .rposition(|cell| *cell != 0)
+        .map_or(0, |last| last + 1);
+    tape[..len].to_vec()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn repetitions_wrap_at_cell_width(source in straight_line_program(), width in cell_width()) {
+        let expected = interpret(&source, &[], width, Mode::Default, PassManager::with_level(0));
+        for level in 1..=3 {
+            prop_assert_eq!(&interpret(&source, &[], width, Mode::Default, PassManager::with_level(level)), &expected);
+            prop_assert_eq!(&compile(&source, &[], width, Mode::Default, PassManager::with_level(level)), &expected);
+        }
+    }
+
+    #[test]
+    fn optimizations_preserve_tape_and_output(
+        (width, source) in cell_width().prop_flat_map(|width| (Just(width), program(width))),
+        input in prop::collection::vec(any::<u8>(), 0..8),
+        mode in mode(),
+    ) {
+        let expected = interpret(&source, &input, width, mode, PassManager::with_level(0));
+        for level in 0..=3 {
+            prop_assert_eq!(&interpret(&source, &input, width, mode, PassManager::with_level(level)), &expected);
+            prop_assert_eq!(&compile(&source, &input, width, mode, PassManager::with_level(level)), &expected);
+        }
+    }
+}
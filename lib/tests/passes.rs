@@ -0,0 +1,161 @@
+//! Optimization passes on hand-built programs
+
+use lib::{
+    config::CellWidth,
+    instructions::ExtendedInstruction,
+    interpreter::Interpreter,
+    ir::Node,
+    lexer::{tokenize_all_with_spans, Span},
+    optimizer::optimize_constants,
+    passes::{Pass, PassManager, PassReport},
+};
+
+fn instruction(instruction: ExtendedInstruction) -> Node {
+    Node::Instruction(instruction, Span::default())
+}
+
+/// Reports of the passes run on a program
+fn reports(source: &str, passes: PassManager) -> Vec<PassReport> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    passes.run(&program, CellWidth::U8).unwrap().1
+}
+
+/// Passes that ran on a program, in order
+fn passes_run(passes: PassManager) -> Vec<Pass> {
+    reports("+", passes)
+        .into_iter()
+        .map(|report| report.pass)
+        .collect()
+}
+
+#[test]
+fn levels_include_the_passes_of_lower_levels() {
+    use Pass::*;
+    let levels: [&[Pass]; 4] = [
+        &[],
+        &[Repetitions, Patterns, DeadLoops],
+        &[
+            Repetitions,
+            Patterns,
+            ScanLoops,
+            MultiplyLoops,
+            DeadLoops,
+            Constants,
+        ],
+        &Pass::ALL,
+    ];
+    for (level, expected) in levels.into_iter().enumerate() {
+        let passes = PassManager::with_level(level as u8);
+        assert_eq!(passes_run(passes.clone()), expected, "-O{level}");
+        for pass in Pass::ALL {
+            assert_eq!(passes.is_enabled(pass), expected.contains(&pass));
+        }
+    }
+    assert_eq!(passes_run(PassManager::with_level(4)), Pass::ALL);
+    assert_eq!(passes_run(PassManager::new()), Pass::ALL);
+}
+
+#[test]
+fn passes_are_toggled_individually_and_run_in_order() {
+    let passes = PassManager::with_level(1)
+        .with_pass(Pass::PointerOffsets, true)
+        .with_pass(Pass::ScanLoops, true)
+        .with_pass(Pass::Patterns, false);
+    assert_eq!(
+        passes_run(passes),
+        [
+            Pass::Repetitions,
+            Pass::ScanLoops,
+            Pass::DeadLoops,
+            Pass::PointerOffsets
+        ]
+    );
+}
+
+#[test]
+fn pass_names_parse_back() {
+    for pass in Pass::ALL {
+        assert_eq!(pass.name().parse(), Ok(pass));
+        assert_eq!(pass.to_string(), pass.name());
+    }
+    assert!("loops".parse::<Pass>().is_err());
+}
+
+#[test]
+fn reports_count_instructions_before_and_after_each_pass() {
+    let report = |pass, changed, before, after| PassReport {
+        pass,
+        changed,
+        before,
+        after,
+    };
+    // `+++[-]>>` folds to `Add [ - ] >>`, then `Add SetZero >>`
+    assert_eq!(
+        reports("+++[-]>>", PassManager::with_level(1)),
+        [
+            report(Pass::Repetitions, true, 8, 5),
+            report(Pass::Patterns, true, 5, 3),
+            report(Pass::DeadLoops, false, 3, 3),
+        ]
+    );
+    // Loop brackets count as instructions in the tree passes
+    assert_eq!(
+        reports(
+            "[-]+[>]",
+            PassManager::with_level(0).with_pass(Pass::ScanLoops, true)
+        ),
+        [report(Pass::ScanLoops, true, 7, 5)]
+    );
+}
+
+#[test]
+fn constants_fold_mul_add_within_displacement_range() {
+    let set = instruction(ExtendedInstruction::SetValue {
+        value: 2,
+        offset: 0,
+    });
+
+    // Near targets become a known value
+    let near = instruction(ExtendedInstruction::MulAdd {
+        offset: 3,
+        factor: 5,
+    });
+    assert_eq!(
+        optimize_constants(&[set.clone(), near], CellWidth::U64),
+        [
+            set.clone(),
+            instruction(ExtendedInstruction::SetValue {
+                value: 10,
+                offset: 3
+            }),
+        ]
+    );
+
+    // Far targets would not fit in a 32-bit displacement in bytes with 64-bit cells
+    let far = instruction(ExtendedInstruction::MulAdd {
+        offset: 1 << 29,
+        factor: 5,
+    });
+    assert_eq!(
+        optimize_constants(&[set.clone(), far.clone()], CellWidth::U64),
+        [set, far]
+    );
+}
+
+/// Output of a program run after another one, on the tape it left
+fn execute_after(previous: &str, source: &str) -> Vec<u8> {
+    let mut interpreter = Interpreter::new().with_output(Vec::new());
+    interpreter
+        .execute(&tokenize_all_with_spans(previous.as_bytes().to_vec()))
+        .unwrap();
+    interpreter
+        .execute(&tokenize_all_with_spans(source.as_bytes().to_vec()))
+        .unwrap();
+    interpreter.into_output()
+}
+
+#[test]
+fn cells_are_not_assumed_zero_on_a_reused_tape() {
+    assert_eq!(execute_after("++", "+."), [3]);
+    assert_eq!(execute_after("+", "[-]."), [0]);
+}
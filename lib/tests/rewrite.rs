@@ -0,0 +1,130 @@
+//! Rewrite rules: the default rules and rules registered by library users
+
+use lib::{
+    config::CellWidth,
+    instructions::{ExtendedInstruction, Instruction},
+    ir::Node,
+    lexer::{tokenize_all_with_spans, Span},
+    optimizer::instructions_to_extended,
+    passes::{Pass, PassManager},
+    rewrite::{rewrite, PatternElement, RewriteRule, RULES},
+};
+
+const SET_ZERO: ExtendedInstruction = ExtendedInstruction::SetZero { offset: 0 };
+
+/// Instructions of a program after applying rewrite rules, without repetitions folded
+fn rewritten(source: &str, rules: &[RewriteRule]) -> Vec<(ExtendedInstruction, Span)> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    rewrite(&instructions_to_extended(&program), rules)
+}
+
+/// Instructions of a program after applying rewrite rules, without their spans
+fn rewritten_instructions(source: &str, rules: &[RewriteRule]) -> Vec<ExtendedInstruction> {
+    rewritten(source, rules)
+        .into_iter()
+        .map(|(instruction, _)| instruction)
+        .collect()
+}
+
+/// Rule removing `>` followed by `<`
+const CANCEL_MOVES: RewriteRule = RewriteRule {
+    name: "cancel-moves",
+    pattern: &[
+        PatternElement::Exact(ExtendedInstruction::Regular(Instruction::MoveRight)),
+        PatternElement::Exact(ExtendedInstruction::Regular(Instruction::MoveLeft)),
+    ],
+    condition: |_| true,
+    replacement: |_| Vec::new(),
+};
+
+/// Rule replacing any loop around a single instruction, to see what the wildcard matches: never run
+const ANY_LOOP: RewriteRule = RewriteRule {
+    name: "any-loop",
+    pattern: &[
+        PatternElement::Exact(ExtendedInstruction::Regular(Instruction::JumpForward)),
+        PatternElement::Any,
+        PatternElement::Exact(ExtendedInstruction::Regular(Instruction::JumpBackwards)),
+    ],
+    condition: |_| true,
+    replacement: |_| vec![ExtendedInstruction::OutputValue(b'!')],
+};
+
+/// Program tree after folding repetitions and applying the default rules
+fn patterns(source: &str) -> Vec<Node> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let passes = PassManager::with_level(0)
+        .with_pass(Pass::Repetitions, true)
+        .with_pass(Pass::Patterns, true);
+    passes.run(&program, CellWidth::U8).unwrap().0
+}
+
+#[test]
+fn default_rules_clear_cells_with_odd_steps() {
+    // The captured value is the folded run of `+` or `-`
+    for source in ["[-]", "[---]", "[+]", "[+++++]"] {
+        assert!(
+            matches!(patterns(source)[..], [Node::Instruction(SET_ZERO, _)]),
+            "{source}"
+        );
+    }
+    // Even steps may never reach 0, and other loops are left alone
+    for source in ["[--]", "[++++]", "[>]", "[-.]"] {
+        assert!(
+            matches!(patterns(source)[..], [Node::Loop { .. }]),
+            "{source}"
+        );
+    }
+}
+
+#[test]
+fn replacements_cover_the_matched_span() {
+    let rewritten = rewritten("+\n [-]", RULES);
+    let span = Span {
+        offset: 3,
+        line: 2,
+        column: 2,
+        len: 3,
+    };
+    assert_eq!(rewritten[1], (SET_ZERO, span));
+}
+
+#[test]
+fn rules_apply_from_left_to_right_in_a_single_pass() {
+    // `>><<` only matches in the middle: the outer moves are not matched again
+    let rules = [CANCEL_MOVES];
+    assert_eq!(
+        rewritten_instructions(">><<", &rules),
+        [
+            ExtendedInstruction::Regular(Instruction::MoveRight),
+            ExtendedInstruction::Regular(Instruction::MoveLeft),
+        ]
+    );
+}
+
+#[test]
+fn registered_rules_have_a_lower_priority() {
+    let program = tokenize_all_with_spans(b"[-][>]><".to_vec());
+    let passes = PassManager::with_level(0)
+        .with_pass(Pass::Patterns, true)
+        .with_rule(ANY_LOOP)
+        .with_rule(CANCEL_MOVES);
+    let (nodes, reports) = passes.run(&program, CellWidth::U8).unwrap();
+
+    // `[-]` is matched by the default rule first, `[>]` by the wildcard, and `><` disappears
+    assert_eq!(
+        nodes,
+        [
+            Node::Instruction(SET_ZERO, rewritten("[-]", RULES)[0].1),
+            Node::Instruction(
+                ExtendedInstruction::OutputValue(b'!'),
+                Span {
+                    offset: 3,
+                    line: 1,
+                    column: 4,
+                    len: 3,
+                }
+            ),
+        ]
+    );
+    assert!(reports[0].changed);
+}
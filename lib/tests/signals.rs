@@ -0,0 +1,90 @@
+//! SIGSEGV handling: faults outside the guard pages go to the handler installed before ours,
+//! without uninstalling it. Kept in its own test binary since it changes process-wide handlers.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lib::{compiler::Compiler, error::BfError, lexer::tokenize_all_with_spans};
+
+/// Page made inaccessible by the test, that the previous handler makes accessible again
+static PAGE: AtomicUsize = AtomicUsize::new(0);
+/// Number of faults seen by the previous handler
+static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Handler installed before the compiler's: recovers from faults in `PAGE` only
+extern "C" fn previous_handler(
+    _signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    let page = PAGE.load(Ordering::SeqCst);
+    let address = unsafe { (*info).si_addr() } as usize;
+    assert!((page..page + page_size()).contains(&address));
+    FAULTS.fetch_add(1, Ordering::SeqCst);
+    unsafe {
+        libc::mprotect(
+            page as *mut _,
+            page_size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+        )
+    };
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Run a program stepping off the left of a guarded tape
+fn fault_left() -> Result<(), BfError> {
+    let program = tokenize_all_with_spans(b"<+".to_vec());
+    let mut compiler = Compiler::new()
+        .with_output(Vec::new())
+        .with_guard_pages(true);
+    compiler.compile(&program)?;
+    compiler.execute()
+}
+
+/// Make `PAGE` inaccessible and write to it, returning once the previous handler recovered
+fn fault_foreign() {
+    let page = PAGE.load(Ordering::SeqCst);
+    unsafe {
+        libc::mprotect(page as *mut _, page_size(), libc::PROT_NONE);
+        std::ptr::write_volatile(page as *mut u8, 1);
+    }
+}
+
+#[test]
+fn foreign_faults_are_chained() {
+    let page = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            page_size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    assert_ne!(page, libc::MAP_FAILED);
+    PAGE.store(page as usize, Ordering::SeqCst);
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = previous_handler as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        assert_eq!(
+            libc::sigaction(libc::SIGSEGV, &action, std::ptr::null_mut()),
+            0
+        );
+    }
+
+    // Installs the compiler's handler on top of the previous one
+    assert!(matches!(fault_left(), Err(BfError::TapeOutOfBounds(_))));
+
+    // Each foreign fault reaches the previous handler, and guard faults are still caught after it
+    for faults in 1..=2 {
+        fault_foreign();
+        assert_eq!(FAULTS.load(Ordering::SeqCst), faults);
+        assert!(matches!(fault_left(), Err(BfError::TapeOutOfBounds(_))));
+    }
+}
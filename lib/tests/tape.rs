@@ -0,0 +1,156 @@
+//! Tape edges: out of bounds accesses, wraparound and growth, in both engines
+
+use lib::{
+    compiler::Compiler,
+    config::{TapeConfig, MAX_TAPE_GROWTH},
+    error::BfError,
+    interpreter::Interpreter,
+    lexer::tokenize_all_with_spans,
+    passes::PassManager,
+};
+
+/// How the JIT compiler catches out of bounds accesses
+#[derive(Debug, Clone, Copy)]
+enum Safety {
+    BoundsCheck,
+    GuardPages,
+}
+
+/// Cells left by a program, or the byte offset of the instruction an out of bounds error is reported at
+fn result(result: Result<(), BfError>, tape: impl FnOnce() -> Vec<u64>) -> Result<Vec<u64>, usize> {
+    match result {
+        Ok(()) => Ok(tape()),
+        Err(BfError::TapeOutOfBounds(span)) => Err(span.offset),
+        Err(error) => panic!("unexpected error: {error}"),
+    }
+}
+
+/// Run a program with the interpreter
+fn interpret(source: &str, tape: TapeConfig, level: u8) -> Result<Vec<u64>, usize> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let mut interpreter = Interpreter::new()
+        .with_output(Vec::new())
+        .with_tape(tape)
+        .with_passes(PassManager::with_level(level));
+    let outcome = interpreter.execute(&program);
+    result(outcome, || interpreter.tape().to_vec())
+}
+
+/// Run a program with the JIT compiler
+fn compile(source: &str, tape: TapeConfig, level: u8, safety: Safety) -> Result<Vec<u64>, usize> {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    let mut compiler = Compiler::new()
+        .with_output(Vec::new())
+        .with_tape(tape)
+        .with_bounds_check(matches!(safety, Safety::BoundsCheck))
+        .with_guard_pages(matches!(safety, Safety::GuardPages))
+        .with_passes(PassManager::with_level(level));
+    compiler.compile(&program).unwrap();
+    let outcome = compiler.execute();
+    result(outcome, || compiler.tape())
+}
+
+fn tape(size: usize) -> TapeConfig {
+    TapeConfig {
+        size,
+        ..TapeConfig::default()
+    }
+}
+
+/// Assert that both engines report leaving the tape at the given offset, at every optimization level
+fn assert_fault(source: &str, tape: TapeConfig, safety: Safety, offset: usize) {
+    for level in 0..=3 {
+        let context = format!("{source:?} at -O{level} with {safety:?}");
+        assert_eq!(interpret(source, tape, level), Err(offset), "{context}");
+        assert_eq!(
+            compile(source, tape, level, safety),
+            Err(offset),
+            "{context}"
+        );
+    }
+}
+
+#[test]
+fn bounds_checks_report_the_move_leaving_the_tape() {
+    let tape = tape(10);
+    let safety = Safety::BoundsCheck;
+    assert_fault("<+", tape, safety, 0);
+    assert_fault(">>+<<<<+", tape, safety, 5);
+    assert_fault("+[<+]", tape, safety, 2);
+    assert_fault("+[>+]", tape, safety, 2);
+    assert_fault(">>>>>>>>>+>+", tape, safety, 10);
+    assert_fault("+.<<<+", tape, safety, 2);
+}
+
+#[test]
+fn guard_pages_report_the_move_leaving_the_tape() {
+    // Guard pages round the tape up to a page: the right edge is only exact for whole pages
+    let tape = tape(1 << 16);
+    let safety = Safety::GuardPages;
+    assert_fault("<+", tape, safety, 0);
+    assert_fault(">>+<<<<+", tape, safety, 5);
+    assert_fault("+[<+]", tape, safety, 2);
+    assert_fault("+[>+]", tape, safety, 2);
+    assert_fault("+.<<<+", tape, safety, 2);
+
+    // Faults can be caught again after the first one
+    assert_fault("<+", tape, safety, 0);
+}
+
+#[test]
+fn wrapping_tapes_never_fault() {
+    let tape = TapeConfig {
+        size: 10,
+        wrapping: true,
+        ..TapeConfig::default()
+    };
+    let cases = [
+        ("<+", [0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+        (">>>>>>>>>>>-<<+", [0, 255, 0, 0, 0, 0, 0, 0, 0, 1]),
+    ];
+    for (source, expected) in cases {
+        for level in 0..=3 {
+            let expected = Ok(expected.to_vec());
+            assert_eq!(interpret(source, tape, level), expected);
+            assert_eq!(compile(source, tape, level, Safety::BoundsCheck), expected);
+        }
+    }
+}
+
+#[test]
+fn growable_tapes_grow_to_the_right() {
+    let tape = TapeConfig {
+        size: 10,
+        growable: true,
+        ..TapeConfig::default()
+    };
+    // Well past a page of cells, so that the JIT tape grows too
+    let source = format!("{}+", ">".repeat(100_000));
+    for level in 0..=3 {
+        let interpreted = interpret(&source, tape, level).unwrap();
+        let compiled = compile(&source, tape, level, Safety::GuardPages).unwrap();
+        for cells in [interpreted, compiled] {
+            assert_eq!(cells[100_000], 1);
+            assert_eq!(cells.iter().sum::<u64>(), 1);
+        }
+    }
+    // Growing never allows moving left of the first cell
+    assert_fault("<+", tape, Safety::GuardPages, 0);
+}
+
+#[test]
+fn growth_is_capped() {
+    // A whole number of pages, so that the JIT tape is not rounded up
+    let tape = TapeConfig {
+        growable: true,
+        ..tape(1 << 15)
+    };
+    let max_size = tape.max_size();
+    assert_eq!(max_size, tape.size + MAX_TAPE_GROWTH);
+
+    // Keeps moving right by 1000 cells until the pointer leaves the grown tape, at the `>` reaching `max_size`
+    let source = format!("+[{}+]", ">".repeat(1000));
+    let offset = 1 + max_size % 1000;
+    assert_eq!(interpret(&source, tape, 3), Err(offset));
+    assert_eq!(compile(&source, tape, 3, Safety::GuardPages), Err(offset));
+}
@@ -0,0 +1,151 @@
+//! Transpiled programs must build without warnings, and behave like the interpreter
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use lib::{
+    config::{CellWidth, EofBehavior},
+    interpreter::Interpreter,
+    lexer::tokenize_all_with_spans,
+    passes::PassManager,
+    transpiler::{Target, Transpiler},
+};
+
+/// Reads up to 3 bytes, prints them and what is left after end of input, a byte wrapping around
+/// at the cell width, and the result of a multiplication
+const PROGRAM: &str = ",>,>,<<.>.>+.>-.++++++++[->++++++++<]>+.";
+const INPUT: &[u8] = b"ab";
+
+const WIDTHS: [CellWidth; 4] = [
+    CellWidth::U8,
+    CellWidth::U16,
+    CellWidth::U32,
+    CellWidth::U64,
+];
+const EOF_BEHAVIORS: [EofBehavior; 3] = [
+    EofBehavior::Unchanged,
+    EofBehavior::Zero,
+    EofBehavior::MinusOne,
+];
+
+/// Temporary file path, unique to this test process
+fn temporary(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("bf-transpiler-{}-{name}", std::process::id()))
+}
+
+/// Output of the program run by the interpreter
+fn interpret(width: CellWidth, eof_behavior: EofBehavior) -> Vec<u8> {
+    let program = tokenize_all_with_spans(PROGRAM.as_bytes().to_vec());
+    let mut interpreter = Interpreter::new()
+        .with_input(INPUT)
+        .with_output(Vec::new())
+        .with_cell_width(width)
+        .with_eof_behavior(eof_behavior);
+    interpreter.execute(&program).unwrap();
+    interpreter.into_output()
+}
+
+/// Source code of the program in the target language
+fn transpile(target: Target, width: CellWidth, eof_behavior: EofBehavior, level: u8) -> String {
+    let program = tokenize_all_with_spans(PROGRAM.as_bytes().to_vec());
+    Transpiler::new()
+        .with_cell_width(width)
+        .with_eof_behavior(eof_behavior)
+        .with_passes(PassManager::with_level(level))
+        .transpile(&program, target)
+        .unwrap()
+}
+
+/// Run a build command, failing on any warning
+fn build(command: &mut Command) {
+    let output = command.output().unwrap();
+    let errors = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success() && errors.is_empty(), "{errors}");
+}
+
+/// Output of an executable run with `INPUT`
+fn run(path: &Path) -> Vec<u8> {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(INPUT).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+#[test]
+fn c_programs_build_cleanly_and_match_the_interpreter() {
+    let (source, executable) = (temporary("program.c"), temporary("c"));
+    for width in WIDTHS {
+        for eof_behavior in EOF_BEHAVIORS {
+            for level in [0, 3] {
+                let code = transpile(Target::C, width, eof_behavior, level);
+                std::fs::write(&source, &code).unwrap();
+                build(
+                    Command::new("cc")
+                        .args(["-std=c99", "-Wall", "-Wextra", "-Wconversion", "-Werror"])
+                        .arg(&source)
+                        .arg("-o")
+                        .arg(&executable),
+                );
+                let context = format!("{width:?}, {eof_behavior:?}, -O{level}\n{code}");
+                assert_eq!(
+                    run(&executable),
+                    interpret(width, eof_behavior),
+                    "{context}"
+                );
+            }
+        }
+    }
+    std::fs::remove_file(source).unwrap();
+    std::fs::remove_file(executable).unwrap();
+}
+
+#[test]
+fn c_input_returns_the_cell_type() {
+    let code = transpile(Target::C, CellWidth::U64, EofBehavior::MinusOne, 0);
+    assert!(code.contains("static uint64_t input(void) {"));
+    assert!(code.contains("return c == EOF ? (uint64_t)-1 : (uint64_t)c;"));
+}
+
+#[test]
+fn rust_modules_build_cleanly_and_match_the_interpreter() {
+    let directory = temporary("rust");
+    std::fs::create_dir_all(&directory).unwrap();
+    let (main, executable) = (directory.join("main.rs"), directory.join("main"));
+    std::fs::write(
+        &main,
+        "mod program;\n\
+         fn main() {\n    \
+             program::run(&mut std::io::stdin(), &mut std::io::stdout()).unwrap();\n\
+         }\n",
+    )
+    .unwrap();
+
+    for width in WIDTHS {
+        for eof_behavior in EOF_BEHAVIORS {
+            let level = 3;
+            let code = transpile(Target::Rust, width, eof_behavior, level);
+            std::fs::write(directory.join("program.rs"), &code).unwrap();
+            build(
+                Command::new("rustc")
+                    .args(["--edition", "2021", "-D", "warnings", "-o"])
+                    .arg(&executable)
+                    .arg(&main),
+            );
+            let context = format!("{width:?}, {eof_behavior:?}\n{code}");
+            assert_eq!(
+                run(&executable),
+                interpret(width, eof_behavior),
+                "{context}"
+            );
+        }
+    }
+    std::fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,127 @@
+//! WebAssembly modules must be valid, and describe the same code in the text and binary formats
+
+use std::path::Path;
+
+use lib::{
+    config::{CellWidth, EofBehavior},
+    error::BfError,
+    lexer::tokenize_all_with_spans,
+    passes::PassManager,
+    transpiler::{Target, Transpiler},
+};
+use wasmparser::{Parser, Payload, Validator};
+
+/// Programs exercising every instruction: input, scans, multiplications and cells on both sides of the pointer
+const PROGRAMS: &[&str] = &[
+    ",[.,]",
+    ">>>,[->+>++<<]>>[<<<+>>>-]<<<.",
+    ">>>>+[<<]-[>>>]<[-]+++.>.<<<<.",
+    ">>[->>+<+<<<+>]>>>>+++[-<+++>]<.",
+];
+
+/// Every example program, and the programs above
+fn programs() -> Vec<(String, String)> {
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples");
+    let mut programs: Vec<(String, String)> = std::fs::read_dir(examples)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "bf"))
+        .map(|path| {
+            let source = std::fs::read_to_string(&path).unwrap();
+            (path.display().to_string(), source)
+        })
+        .collect();
+    programs.extend(
+        PROGRAMS
+            .iter()
+            .map(|source| (source.to_string(), source.to_string())),
+    );
+    programs
+}
+
+/// Operators of the function bodies of a binary module
+fn operators(module: &[u8]) -> Vec<String> {
+    let mut operators = Vec::new();
+    for payload in Parser::new(0).parse_all(module) {
+        if let Payload::CodeSectionEntry(body) = payload.unwrap() {
+            for operator in body.get_operators_reader().unwrap() {
+                operators.push(format!("{:?}", operator.unwrap()));
+            }
+        }
+    }
+    operators
+}
+
+#[test]
+fn wasm_modules_are_valid() {
+    let widths = [
+        CellWidth::U8,
+        CellWidth::U16,
+        CellWidth::U32,
+        CellWidth::U64,
+    ];
+    let eof_behaviors = [
+        EofBehavior::Unchanged,
+        EofBehavior::Zero,
+        EofBehavior::MinusOne,
+    ];
+
+    for (name, source) in programs() {
+        let program = tokenize_all_with_spans(source.into_bytes());
+        for width in widths {
+            for eof_behavior in eof_behaviors {
+                for level in [0, 3] {
+                    let mut transpiler = Transpiler::new()
+                        .with_cell_width(width)
+                        .with_eof_behavior(eof_behavior)
+                        .with_passes(PassManager::with_level(level));
+                    let binary = transpiler.compile_wasm(&program).unwrap();
+                    let text = transpiler.transpile(&program, Target::Wat).unwrap();
+                    let parsed = wat::parse_str(&text).unwrap();
+
+                    let context = format!("{name} ({width:?}, {eof_behavior:?}, -O{level})");
+                    Validator::new()
+                        .validate_all(&binary)
+                        .unwrap_or_else(|error| panic!("{context}: {error}"));
+                    Validator::new()
+                        .validate_all(&parsed)
+                        .unwrap_or_else(|error| panic!("{context}: {error}"));
+                    assert_eq!(operators(&binary), operators(&parsed), "{context}");
+                }
+            }
+        }
+    }
+}
+
+/// Text of the module of a program with 10 cells, without optimizations
+fn unoptimized_text(source: &str) -> String {
+    let program = tokenize_all_with_spans(source.as_bytes().to_vec());
+    Transpiler::new()
+        .with_tape_size(10)
+        .with_passes(PassManager::with_level(0))
+        .transpile(&program, Target::Wat)
+        .unwrap()
+}
+
+#[test]
+fn wasm_moves_are_checked_against_the_tape_size() {
+    // Moving right needs room for one more cell, and moving left a cell before the pointer
+    let right = unoptimized_text(">");
+    assert!(right.contains("i32.const 9\n    i32.ge_u\n    if\n      unreachable"));
+    let left = unoptimized_text("<");
+    assert!(left.contains("i32.const 1\n    i32.lt_u\n    if\n      unreachable"));
+}
+
+#[test]
+fn wasm_tapes_must_fit_in_32_bit_addresses() {
+    let program = tokenize_all_with_spans(b"+".to_vec());
+    let mut transpiler = Transpiler::new()
+        .with_tape_size(1 << 29)
+        .with_cell_width(CellWidth::U64);
+    assert!(matches!(
+        transpiler.compile_wasm(&program),
+        Err(BfError::TapeTooLarge)
+    ));
+    let mut transpiler = transpiler.with_cell_width(CellWidth::U32);
+    assert!(transpiler.compile_wasm(&program).is_ok());
+}